@@ -0,0 +1,102 @@
+//! Compares the generic dispatch loop against the specialized instructions
+//! produced by the peephole optimizer.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::time::{Duration, Instant};
+
+use lox::chunk::Chunk;
+use lox::opcode::OpCode;
+use lox::optimizer::optimize;
+use lox::value::Value;
+use lox::vm::VM;
+
+const RUNS: usize = 10;
+const LENGTH: usize = 20_000;
+
+/// `0 + 1 + 1 + ...`
+fn arithmetic() -> Chunk {
+    let mut chunk = Chunk::new("arithmetic");
+    let zero = chunk.add_const(Value::Number(0.0)) as u8;
+    let one = chunk.add_const(Value::Number(1.0)) as u8;
+    chunk.write_opcode(OpCode::Constant, 1);
+    chunk.write(zero, 1);
+    for _ in 0..LENGTH {
+        chunk.write_opcode(OpCode::Constant, 1);
+        chunk.write(one, 1);
+        chunk.write_opcode(OpCode::Add, 1);
+    }
+    chunk.write_opcode(OpCode::Return, 1);
+    chunk
+}
+
+/// `(((0 < 1) < 1) < 1) ...`
+fn comparison() -> Chunk {
+    let mut chunk = Chunk::new("comparison");
+    let zero = chunk.add_const(Value::Number(0.0)) as u8;
+    let one = chunk.add_const(Value::Number(1.0)) as u8;
+    chunk.write_opcode(OpCode::Constant, 1);
+    chunk.write(zero, 1);
+    for _ in 0..LENGTH {
+        chunk.write_opcode(OpCode::Constant, 1);
+        chunk.write(one, 1);
+        chunk.write_opcode(OpCode::Less, 1);
+    }
+    chunk.write_opcode(OpCode::Return, 1);
+    chunk
+}
+
+/// `"" + "ab" + "ab" + ... == ""`
+fn string_building() -> Chunk {
+    let mut chunk = Chunk::new("string_building");
+    let empty = chunk.add_const(Value::String(String::new())) as u8;
+    let ab = chunk.add_const(Value::String("ab".to_string())) as u8;
+    chunk.write_opcode(OpCode::Constant, 1);
+    chunk.write(empty, 1);
+    for _ in 0..LENGTH / 10 {
+        chunk.write_opcode(OpCode::Constant, 1);
+        chunk.write(ab, 1);
+        chunk.write_opcode(OpCode::Add, 1);
+    }
+    chunk.write_opcode(OpCode::Constant, 1);
+    chunk.write(empty, 1);
+    chunk.write_opcode(OpCode::Equal, 1);
+    chunk.write_opcode(OpCode::Return, 1);
+    chunk
+}
+
+fn median_time(chunk: &Chunk) -> Duration {
+    let mut vm = VM::default();
+    vm.set_stdout(std::io::sink());
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let chunk = chunk.clone();
+            let start = Instant::now();
+            vm.interpret_chunk(chunk);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn main() {
+    let mut results = Vec::new();
+    for generic in [arithmetic(), comparison(), string_building()] {
+        let mut specialized = generic.clone();
+        optimize(&mut specialized);
+        results.push((generic.name.clone(), median_time(&generic), median_time(&specialized)));
+    }
+
+    println!();
+    println!("{:<16} {:>12} {:>12} {:>8}", "benchmark", "generic", "specialized", "speedup");
+    for (name, generic, specialized) in results {
+        println!(
+            "{:<16} {:>12?} {:>12?} {:>7.2}x",
+            name,
+            generic,
+            specialized,
+            generic.as_secs_f64() / specialized.as_secs_f64()
+        );
+    }
+}
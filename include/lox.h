@@ -0,0 +1,82 @@
+#ifndef LOX_H
+#define LOX_H
+
+/* Generated by `rlox header` from src/ffi.rs. Do not edit. */
+
+#include <stdbool.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct LoxVM LoxVM;
+typedef struct LoxValue LoxValue;
+
+#define LOX_OK 0
+#define LOX_COMPILE_ERROR 1
+#define LOX_RUNTIME_ERROR 2
+#define LOX_ABORTED 3
+
+#define LOX_NIL 0
+#define LOX_BOOL 1
+#define LOX_NUMBER 2
+#define LOX_STRING 3
+#define LOX_NATIVE 4
+#define LOX_LIST 5
+#define LOX_MAP 6
+
+/* A native function implemented by the host. It receives the userdata given at
+   registration and returns a new value, or null to raise a runtime error. */
+typedef LoxValue *(*LoxNativeFn)(void *userdata, const LoxValue *const *args, int argc);
+
+LoxVM *lox_vm_new(void);
+
+/* Frees a VM. Null is ignored. */
+void lox_vm_free(LoxVM *vm);
+
+/* Compiles and runs source, returning one of the LOX_OK… result codes.
+   A failure inside the VM itself is reported as a runtime error. */
+int lox_interpret(LoxVM *vm, const char *source);
+
+/* The error messages from the last lox_interpret call, or null if it succeeded. */
+const char *lox_last_error(const LoxVM *vm);
+
+/* Defines a global function taking arity arguments that calls function. */
+bool lox_define_native(LoxVM *vm, const char *name, int arity, LoxNativeFn function, void *userdata);
+
+/* Returns a new value holding the global name, or null if it is undefined. */
+LoxValue *lox_get_global(const LoxVM *vm, const char *name);
+
+/* Sets the global name to a copy of value. */
+bool lox_set_global(LoxVM *vm, const char *name, const LoxValue *value);
+
+LoxValue *lox_value_nil(void);
+
+LoxValue *lox_value_bool(bool value);
+
+LoxValue *lox_value_number(double value);
+
+/* Copies value into a new string value, or returns null if it is not valid UTF-8. */
+LoxValue *lox_value_string(const char *value);
+
+/* Frees a value. Null is ignored. */
+void lox_value_free(LoxValue *value);
+
+/* One of LOX_NIL, LOX_BOOL, LOX_NUMBER, LOX_STRING, LOX_NATIVE, LOX_LIST or LOX_MAP. */
+int lox_value_type(const LoxValue *value);
+
+/* Whether the value is truthy, so it works for every type. */
+bool lox_value_as_bool(const LoxValue *value);
+
+/* The number held by the value, or NaN for other types. */
+double lox_value_as_number(const LoxValue *value);
+
+/* The value printed as a string, owned by value and valid until it is freed,
+   or null if it could not be formatted. */
+const char *lox_value_as_string(const LoxValue *value);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
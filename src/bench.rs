@@ -0,0 +1,183 @@
+use std::{fs, io, path::Path, time::{Duration, Instant}};
+
+use crate::{json::Json, vm::VM};
+
+/// A Lox program to be timed.
+pub struct Benchmark {
+    pub name: String,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub name: String,
+    pub median: Duration,
+    pub min: Duration,
+    pub instructions: usize,
+}
+
+/// Loads every `.lox` file in `dir`, sorted by name.
+pub fn load_benchmarks(dir: &Path) -> io::Result<Vec<Benchmark>> {
+    let mut benchmarks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "lox") {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            benchmarks.push(Benchmark { name, source: fs::read_to_string(&path)? });
+        }
+    }
+    benchmarks.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(benchmarks)
+}
+
+/// Defines the globals benchmarks take their operands from. Literal operands
+/// would be folded into a single constant by the compiler, leaving the VM
+/// nothing to execute.
+fn define_inputs(vm: &mut VM) {
+    for (name, value) in [("a", 42.0), ("b", 7.0), ("c", 0.5), ("d", 13.0)] {
+        vm.set_global(name, value);
+    }
+    vm.set_global("word", "lox ");
+}
+
+/// Interprets the benchmark `runs` times on a fresh VM, timing compilation and execution together.
+pub fn run_benchmark(benchmark: &Benchmark, runs: usize) -> BenchResult {
+    let mut vm = VM::default();
+    vm.set_stdout(io::sink());
+    define_inputs(&mut vm);
+    let mut times: Vec<Duration> = Vec::with_capacity(runs);
+    for _ in 0..runs.max(1) {
+        let start = Instant::now();
+        vm.interpret(&benchmark.source);
+        times.push(start.elapsed());
+    }
+    times.sort();
+
+    BenchResult {
+        name: benchmark.name.clone(),
+        median: times[times.len() / 2],
+        min: times[0],
+        instructions: vm.instructions_executed(),
+    }
+}
+
+pub fn report(results: &[BenchResult]) -> String {
+    let mut out = format!("{:<24} {:>12} {:>12} {:>14}\n", "benchmark", "median", "min", "instructions");
+    for result in results {
+        out += &format!(
+            "{:<24} {:>12?} {:>12?} {:>14}\n",
+            result.name, result.median, result.min, result.instructions
+        );
+    }
+    out
+}
+
+pub fn to_json(results: &[BenchResult]) -> Json {
+    Json::Array(
+        results
+            .iter()
+            .map(|result| {
+                Json::object(vec![
+                    ("name", result.name.as_str().into()),
+                    ("median_ns", (result.median.as_nanos() as f64).into()),
+                    ("min_ns", (result.min.as_nanos() as f64).into()),
+                    ("instructions", result.instructions.into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+pub fn from_json(json: &Json) -> Result<Vec<BenchResult>, String> {
+    let items = json.as_array().ok_or("Baseline must be a JSON array")?;
+    items
+        .iter()
+        .map(|item| {
+            let field = |key: &str| {
+                item.get(key)
+                    .and_then(Json::as_f64)
+                    .ok_or_else(|| format!("Baseline entry is missing '{}'", key))
+            };
+            Ok(BenchResult {
+                name: item
+                    .get("name")
+                    .and_then(Json::as_str)
+                    .ok_or("Baseline entry is missing 'name'")?
+                    .to_string(),
+                median: Duration::from_nanos(field("median_ns")? as u64),
+                min: Duration::from_nanos(field("min_ns")? as u64),
+                instructions: field("instructions")? as usize,
+            })
+        })
+        .collect()
+}
+
+/// Describes every benchmark whose median time grew by more than `threshold`
+/// (e.g. `0.1` for 10%) or that executes more instructions than in the baseline.
+pub fn regressions(results: &[BenchResult], baseline: &[BenchResult], threshold: f64) -> Vec<String> {
+    let mut found = Vec::new();
+    for result in results {
+        let Some(base) = baseline.iter().find(|base| base.name == result.name) else {
+            continue;
+        };
+        let ratio = result.median.as_secs_f64() / base.median.as_secs_f64();
+        if ratio > 1.0 + threshold {
+            found.push(format!(
+                "{}: median {:?} is {:.1}% slower than baseline {:?}",
+                result.name,
+                result.median,
+                (ratio - 1.0) * 100.0,
+                base.median
+            ));
+        }
+        if result.instructions > base.instructions {
+            found.push(format!(
+                "{}: {} instructions executed, baseline was {}",
+                result.name, result.instructions, base.instructions
+            ));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &str, median_ms: u64, instructions: usize) -> BenchResult {
+        BenchResult {
+            name: name.to_string(),
+            median: Duration::from_millis(median_ms),
+            min: Duration::from_millis(median_ms / 2),
+            instructions,
+        }
+    }
+
+    #[test]
+    fn test_baseline_round_trip() {
+        let results = vec![result("a", 10, 100), result("b", 3, 7)];
+        let json = Json::parse(&to_json(&results).to_string()).unwrap();
+        assert_eq!(from_json(&json).unwrap(), results);
+    }
+
+    #[test]
+    fn test_benchmarks_run_in_the_vm() {
+        let benchmarks = load_benchmarks(&Path::new(env!("CARGO_MANIFEST_DIR")).join("bench")).unwrap();
+        assert!(!benchmarks.is_empty());
+        for benchmark in &benchmarks {
+            // a folded benchmark would only execute CONSTANT and RETURN
+            let result = run_benchmark(benchmark, 1);
+            assert!(result.instructions > 100, "{} executed {} instructions", result.name, result.instructions);
+        }
+    }
+
+    #[test]
+    fn test_regressions() {
+        let baseline = vec![result("a", 10, 100), result("b", 10, 100)];
+        let results = vec![result("a", 10, 100), result("b", 12, 101), result("c", 50, 1)];
+        let found = regressions(&results, &baseline, 0.1);
+        assert_eq!(found.len(), 2);
+        assert!(found[0].starts_with("b: median"));
+        assert!(found[1].starts_with("b: 101 instructions"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::table::Table;
+use crate::value::{resolve_index, Native, Value};
+
+type Builtin = fn(&[Value]) -> Result<Value, String>;
+
+/// The natives every VM provides, as (name, arity, function). Globals of the
+/// same name take precedence.
+const BUILTINS: &[(&str, usize, Builtin)] = &[
+    ("len", 1, len),
+    ("push", 2, push),
+    ("pop", 1, pop),
+    ("insert", 3, insert),
+    ("remove", 2, remove),
+    ("slice", 3, slice),
+    ("has", 2, has),
+    ("keys", 1, keys),
+    ("values", 1, values),
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.iter().any(|(builtin, _, _)| *builtin == name)
+}
+
+pub fn builtins() -> HashMap<String, Value> {
+    BUILTINS
+        .iter()
+        .map(|&(name, arity, function)| (name.to_string(), Value::Native(Rc::new(Native::new(name, arity, function)))))
+        .collect()
+}
+
+/// The number of items in a list or map, or characters in a string.
+fn len(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::List(list) => Ok(Value::Number(list.borrow().len() as f64)),
+        Value::Map(map) => Ok(Value::Number(map.borrow().len() as f64)),
+        Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+        value => Err(format!("Cannot take the length of {}", value)),
+    }
+}
+
+fn expect_list(value: &Value) -> Result<&Rc<RefCell<Vec<Value>>>, String> {
+    match value {
+        Value::List(list) => Ok(list),
+        _ => Err(format!("Expected a list but got {}", value)),
+    }
+}
+
+/// Appends a value and returns the list, so calls can be chained.
+fn push(args: &[Value]) -> Result<Value, String> {
+    expect_list(&args[0])?.borrow_mut().push(args[1].clone());
+    Ok(args[0].clone())
+}
+
+/// Removes and returns the last item.
+fn pop(args: &[Value]) -> Result<Value, String> {
+    expect_list(&args[0])?.borrow_mut().pop().ok_or_else(|| "Cannot pop from an empty list".to_string())
+}
+
+/// Inserts a value before `index` and returns the list. The index may also be
+/// the length of the list, which appends.
+fn insert(args: &[Value]) -> Result<Value, String> {
+    let mut list = expect_list(&args[0])?.borrow_mut();
+    let len = list.len();
+    let position = match &args[1] {
+        Value::Number(n) if *n == len as f64 => len,
+        index => resolve_index(index, len)?,
+    };
+    list.insert(position, args[2].clone());
+    drop(list);
+    Ok(args[0].clone())
+}
+
+fn expect_map(value: &Value) -> Result<&Rc<RefCell<Table>>, String> {
+    match value {
+        Value::Map(map) => Ok(map),
+        _ => Err(format!("Expected a map but got {}", value)),
+    }
+}
+
+/// Removes and returns the item at an index of a list or under a key of a map.
+fn remove(args: &[Value]) -> Result<Value, String> {
+    if let Value::Map(map) = &args[0] {
+        return map.borrow_mut().remove(&args[1])?.ok_or_else(|| format!("Key {} not found", args[1].repr()));
+    }
+    let mut list = expect_list(&args[0])?.borrow_mut();
+    let position = resolve_index(&args[1], list.len())?;
+    Ok(list.remove(position))
+}
+
+/// Whether a map has an entry for the key.
+fn has(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Bool(expect_map(&args[0])?.borrow().get(&args[1])?.is_some()))
+}
+
+/// A map's keys as a list, in the order they were first inserted.
+fn keys(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::list(expect_map(&args[0])?.borrow().iter().map(|(key, _)| key.clone()).collect()))
+}
+
+/// A map's values as a list, in the same order as `keys`.
+fn values(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::list(expect_map(&args[0])?.borrow().iter().map(|(_, value)| value.clone()).collect()))
+}
+
+/// The items (or characters) from `start` up to but not including `end`, as a
+/// new list (or string). Negative bounds count from the end, out of range
+/// bounds are clamped and `nil` means the start or end.
+fn slice(args: &[Value]) -> Result<Value, String> {
+    let bound = |value: &Value, len: usize, default: usize| -> Result<usize, String> {
+        match value {
+            Value::Nil => Ok(default),
+            Value::Number(n) if n.fract() == 0.0 => {
+                let n = if *n < 0.0 { n + len as f64 } else { *n };
+                Ok(n.clamp(0.0, len as f64) as usize)
+            }
+            _ => Err(format!("Slice bounds must be integers or nil, not {}", value)),
+        }
+    };
+    match &args[0] {
+        Value::List(list) => {
+            let list = list.borrow();
+            let start = bound(&args[1], list.len(), 0)?;
+            let end = bound(&args[2], list.len(), list.len())?.max(start);
+            Ok(Value::list(list[start..end].to_vec()))
+        }
+        Value::String(s) => {
+            let len = s.chars().count();
+            let start = bound(&args[1], len, 0)?;
+            let end = bound(&args[2], len, len)?.max(start);
+            Ok(Value::String(s.chars().skip(start).take(end - start).collect()))
+        }
+        value => Err(format!("Cannot slice {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+        match &builtins()[name] {
+            Value::Native(native) => native.call(args),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_list_functions() {
+        let list = Value::list(vec![Value::Number(1.0), Value::Number(2.0)]);
+        call("push", &[list.clone(), Value::Number(3.0)]).unwrap();
+        call("insert", &[list.clone(), Value::Number(0.0), Value::Number(0.0)]).unwrap();
+        call("insert", &[list.clone(), Value::Number(4.0), Value::Number(4.0)]).unwrap();
+        assert_eq!(list.to_string(), "[0, 1, 2, 3, 4]");
+        assert_eq!(call("remove", &[list.clone(), Value::Number(-2.0)]), Ok(Value::Number(3.0)));
+        assert_eq!(call("pop", std::slice::from_ref(&list)), Ok(Value::Number(4.0)));
+        assert_eq!(call("len", std::slice::from_ref(&list)), Ok(Value::Number(3.0)));
+        let slice = |target: &Value, start: Value, end: Value| call("slice", &[target.clone(), start, end]).unwrap();
+        assert_eq!(slice(&list, Value::Number(1.0), Value::Nil).to_string(), "[1, 2]");
+        assert_eq!(slice(&list, Value::Number(-9.0), Value::Number(-1.0)).to_string(), "[0, 1]");
+        assert_eq!(slice(&Value::from("héllo"), Value::Number(1.0), Value::Number(3.0)), Value::from("él"));
+
+        assert_eq!(call("pop", &[Value::list(vec![])]).unwrap_err(), "Cannot pop from an empty list");
+        assert_eq!(
+            call("insert", &[list.clone(), Value::Number(5.0), Value::Nil]).unwrap_err(),
+            "Index 5 out of bounds for length 3"
+        );
+        assert!(call("push", &[Value::Nil, Value::Nil]).is_err());
+        assert!(is_builtin("len") && !is_builtin("print"));
+    }
+
+    #[test]
+    fn test_map_functions() {
+        let mut table = Table::new();
+        table.insert(Value::from("b"), Value::Number(1.0)).unwrap();
+        table.insert(Value::Number(2.0), Value::Nil).unwrap();
+        let map = Value::map(table);
+        assert_eq!(call("has", &[map.clone(), Value::Number(2.0)]), Ok(Value::Bool(true)));
+        assert_eq!(call("has", &[map.clone(), Value::from("2")]), Ok(Value::Bool(false)));
+        assert_eq!(call("keys", std::slice::from_ref(&map)).unwrap().to_string(), "[\"b\", 2]");
+        assert_eq!(call("values", std::slice::from_ref(&map)).unwrap().to_string(), "[1, nil]");
+        assert_eq!(call("remove", &[map.clone(), Value::from("b")]), Ok(Value::Number(1.0)));
+        assert_eq!(call("remove", &[map.clone(), Value::from("b")]).unwrap_err(), "Key \"b\" not found");
+        assert_eq!(call("len", std::slice::from_ref(&map)), Ok(Value::Number(1.0)));
+        assert!(call("has", &[map.clone(), Value::list(vec![])]).is_err());
+        assert!(call("keys", &[Value::list(vec![])]).is_err());
+    }
+}
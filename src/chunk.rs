@@ -1,10 +1,13 @@
-use crate::{opcode::OpCode, value::Value};
+use crate::{diagnostic::Span, opcode::OpCode, value::Value};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pub name: String,
     pub code: Vec<u8>,
     pub lines: Vec<usize>,
+    /// The source each byte of code was compiled from, for pointing at the
+    /// expression that failed. Not serialized, since the source isn't either.
+    pub spans: Vec<Span>,
     pub constants: Vec<Value>,
 }
 
@@ -14,13 +17,19 @@ impl Chunk {
             name: name.to_string(), 
             code: Vec::new(), 
             lines: Vec::new(), 
+            spans: Vec::new(),
             constants: Vec::new() 
         }
     }
 
     pub fn write(&mut self, byte: u8, line: usize) {
+        self.write_spanned(byte, line, Span::default());
+    }
+
+    pub fn write_spanned(&mut self, byte: u8, line: usize, span: Span) {
         self.code.push(byte);
         self.lines.push(line);
+        self.spans.push(span);
     }
 
     pub fn write_opcode(&mut self, opcode: OpCode, line: usize) {
@@ -35,6 +44,162 @@ impl Chunk {
     pub fn get_const(&self, index: usize) -> Value {
         self.constants[index].clone()
     }
+
+    /// Encodes the chunk in the `.loxc` format:
+    /// a magic header, the format version, a checksum of the body and then the body itself.
+    pub fn serialize(&self) -> Result<Vec<u8>, String> {
+        let mut body = Vec::new();
+        write_str(&mut body, &self.name);
+        write_u32(&mut body, self.code.len() as u32);
+        body.extend_from_slice(&self.code);
+        write_u32(&mut body, self.lines.len() as u32);
+        for &line in &self.lines {
+            write_u32(&mut body, line as u32);
+        }
+        write_u32(&mut body, self.constants.len() as u32);
+        for constant in &self.constants {
+            match constant {
+                Value::Nil => body.push(TAG_NIL),
+                Value::Bool(b) => {
+                    body.push(TAG_BOOL);
+                    body.push(*b as u8);
+                }
+                Value::Number(n) => {
+                    body.push(TAG_NUMBER);
+                    body.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::String(s) => {
+                    body.push(TAG_STRING);
+                    write_str(&mut body, s);
+                }
+                Value::List(_) => return Err("Cannot serialize a list".to_string()),
+                Value::Map(_) => return Err("Cannot serialize a map".to_string()),
+                Value::Native(native) => {
+                    return Err(format!("Cannot serialize native function '{}'", native.name));
+                }
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&checksum(&body).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    /// Decodes a chunk previously written by `serialize`.
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, String> {
+        if !is_serialized(bytes) {
+            return Err("Not a compiled Lox file".to_string());
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err("Truncated header".to_string());
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != FORMAT_VERSION {
+            return Err(format!("Unsupported format version {} (expected {})", version, FORMAT_VERSION));
+        }
+        let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+        let body = &bytes[HEADER_LEN..];
+        if checksum(body) != expected {
+            return Err("Checksum mismatch".to_string());
+        }
+
+        let mut reader = Reader { bytes: body, pos: 0 };
+        let name = reader.read_str()?;
+        let code_len = reader.read_u32()? as usize;
+        let code = reader.read_bytes(code_len)?.to_vec();
+        let line_count = reader.read_u32()? as usize;
+        if line_count != code_len {
+            return Err("Line table does not match code length".to_string());
+        }
+        let mut lines = Vec::with_capacity(line_count);
+        for _ in 0..line_count {
+            lines.push(reader.read_u32()? as usize);
+        }
+        let const_count = reader.read_u32()? as usize;
+        let mut constants = Vec::new();
+        for _ in 0..const_count {
+            let constant = match reader.read_u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_BOOL => Value::Bool(reader.read_u8()? != 0),
+                TAG_NUMBER => {
+                    let mut buf = [0; 8];
+                    buf.copy_from_slice(reader.read_bytes(8)?);
+                    Value::Number(f64::from_le_bytes(buf))
+                }
+                TAG_STRING => Value::String(reader.read_str()?),
+                tag => return Err(format!("Unknown constant tag {}", tag)),
+            };
+            constants.push(constant);
+        }
+        if reader.pos != body.len() {
+            return Err("Trailing bytes after chunk".to_string());
+        }
+
+        let spans = vec![Span::default(); code.len()];
+        Ok(Chunk { name, code, lines, spans, constants })
+    }
+}
+
+const MAGIC: &[u8; 4] = b"LOXC";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 10;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+
+/// Returns true if the bytes start with the `.loxc` magic header.
+pub fn is_serialized(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// 32 bit FNV-1a hash of the body.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < len {
+            return Err("Unexpected end of file".to_string());
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_str(&mut self) -> Result<String, String> {
+        let len = self.read_u32()? as usize;
+        String::from_utf8(self.read_bytes(len)?.to_vec()).map_err(|_| "Invalid UTF-8 in string".to_string())
+    }
 }
 
 
@@ -43,3 +208,31 @@ impl Default for Chunk {
         Self::new("chunk")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let chunk = compile("-\"a\" + \"b\" == nil").unwrap();
+        let bytes = chunk.serialize().unwrap();
+        assert!(is_serialized(&bytes));
+        let decoded = Chunk::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.name, chunk.name);
+        assert_eq!(decoded.code, chunk.code);
+        assert_eq!(decoded.lines, chunk.lines);
+        assert_eq!(decoded.constants, chunk.constants);
+    }
+
+    #[test]
+    fn test_deserialize_rejects_corruption() {
+        let mut bytes = compile("1.5 * 2").unwrap().serialize().unwrap();
+        assert!(Chunk::deserialize(&bytes[..bytes.len() - 1]).is_err());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert_eq!(Chunk::deserialize(&bytes).unwrap_err(), "Checksum mismatch");
+        assert!(Chunk::deserialize(b"1 + 2").is_err());
+    }
+}
@@ -1,65 +1,144 @@
-use crate::{chunk::Chunk, opcode::OpCode, scanner::{Scanner, Token, TokenType}, value::Value};
+use std::fmt::Display;
 
-pub fn compile(source: &str) -> Result<Chunk, ()> {
+use crate::{chunk::Chunk, diagnostic::{line_column, Diagnostic, Span}, opcode::OpCode, optimizer::optimize, value::Value};
+use crate::vm::STACK_MAX;
+use crate::scanner::{
+    closing_indent, is_triple, number_value, string_part_value, string_value, Scanner, Token, TokenType,
+};
+
+/// Compiles the source into a chunk, or returns every error reported along the way.
+pub fn compile(source: &str) -> Result<Chunk, Vec<CompileError>> {
     let mut parser = Parser::new(source);
     advance(&mut parser);
     expression(&mut parser);
-    advance(&mut parser);
     // ensure no tokens left
     if parser.current.token_type != TokenType::Eof {
-        error_at_current(&mut parser, "Expect end of expression");
-        return Err(());
+        if let Some(error) = error_at_current(&mut parser, "Expect end of expression") {
+            error.help = Some("did you forget an operator between the expressions?".to_string());
+        }
     }
+    // the return is attributed to the last token of the expression rather than the end of the file
     end_compiler(&mut parser);
+    if parser.had_error {
+        return Err(parser.errors);
+    }
     Ok(parser.chunk)
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    /// 1-based, counted in characters.
+    pub column: usize,
+    /// The source the error is about. Errors at the end of the input point just past the last token.
+    pub span: Span,
+    /// Where on the line the error was found, e.g. `at 'x'` or `at end`.
+    pub location: String,
+    pub message: String,
+    pub notes: Vec<String>,
+    /// A suggestion for fixing the error, e.g. `did you forget a ')'?`.
+    pub help: Option<String>,
+}
+
+impl CompileError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(&self.message, self.line, self.span);
+        diagnostic.notes = self.notes.clone();
+        diagnostic.help = self.help.clone();
+        diagnostic
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] Error", self.line)?;
+        if !self.location.is_empty() {
+            write!(f, " {}", self.location)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
 struct Parser<'src> {
+    source: &'src str,
     scanner: Scanner<'src>,
     chunk: Chunk,
     current: Token<'src>,
     previous: Token<'src>,
     had_error: bool,
     panic_mode: bool,
+    errors: Vec<CompileError>,
+    // offset where the left operand of the infix expression being parsed starts
+    infix_start: usize,
+    // and the byte offset of that operand in the source
+    infix_offset: usize,
+    // whether the expression being parsed may be the target of an assignment
+    can_assign: bool,
+    // values the enclosing expressions keep on the stack while the current one
+    // is evaluated, such as the left operand of a binary operator
+    depth: usize,
 }
 
 impl<'src> Parser<'src> {
     pub fn new(source: &'src str) -> Self {
         Self { 
+            source,
             scanner: Scanner::new(source), 
             chunk: Chunk::new("main"), 
             current: Token::default(), 
             previous: Token::default(),
             had_error: false,
             panic_mode: false,
+            errors: Vec::new(),
+            infix_start: 0,
+            infix_offset: 0,
+            can_assign: false,
+            depth: 0,
         }
     } 
 }
 
-fn error_at<'src>(parser: &mut Parser<'src>, token: Token<'src>, message: &str) {
+/// Records an error at `token` and returns it so a caller can add notes or help,
+/// or returns `None` if the error was suppressed because the parser is panicking.
+fn error_at<'p, 'src>(parser: &'p mut Parser<'src>, token: Token<'src>, message: &str) -> Option<&'p mut CompileError> {
     // if things are panicking, don't print anything
     if parser.panic_mode {
-        return;
+        return None;
     }
     parser.panic_mode = true;
-    eprint!("[line {}] Error", token.line);
-    if token.token_type == TokenType::Eof {
-        eprint!(" at end");
-    } else if token.token_type == TokenType::Error {
-        // Nothing.
-    } else {
-        eprint!(" at '{}'", token.lexeme);
-    }
-    eprintln!(": {}", message);
+    let location: String = match token.token_type {
+        TokenType::Eof => "at end".to_string(),
+        TokenType::Error => String::new(),
+        _ => format!("at '{}'", token.lexeme),
+    };
+    let (line, column, span) = match token.token_type {
+        // point just past the end of the code rather than at trailing whitespace
+        TokenType::Eof => {
+            let offset = parser.source[..token.offset].trim_end().len();
+            let (line, column) = line_column(parser.source, offset);
+            (line, column, Span::new(offset, 1))
+        }
+        _ => (token.line, token.column, Span::new(token.offset, token.length.max(1))),
+    };
     parser.had_error = true;
+    parser.errors.push(CompileError {
+        line,
+        column,
+        span,
+        location,
+        message: message.to_string(),
+        notes: Vec::new(),
+        help: None,
+    });
+    parser.errors.last_mut()
 }
 
-fn error<'src>(parser: &mut Parser<'src>, message: &str) {
-    error_at(parser, parser.previous, message);
+fn error<'p, 'src>(parser: &'p mut Parser<'src>, message: &str) -> Option<&'p mut CompileError> {
+    error_at(parser, parser.previous, message)
 }
 
-fn error_at_current<'src>(parser: &mut Parser<'src>, message: &str) {
-    error_at(parser, parser.current, message);
+fn error_at_current<'p, 'src>(parser: &'p mut Parser<'src>, message: &str) -> Option<&'p mut CompileError> {
+    error_at(parser, parser.current, message)
 }
 
 fn consume<'src>(parser: &mut Parser<'src>, token_type: TokenType, message: &str) -> bool {
@@ -67,12 +146,43 @@ fn consume<'src>(parser: &mut Parser<'src>, token_type: TokenType, message: &str
         advance(parser);
         return true;
     }
-    error_at_current(parser, message);
+    if let Some(error) = error_at_current(parser, message) {
+        let closing = match token_type {
+            TokenType::RightParen => Some(')'),
+            TokenType::RightBracket => Some(']'),
+            TokenType::RightBrace => Some('}'),
+            _ => None,
+        };
+        error.help = closing.map(|closing| format!("did you forget a '{}'?", closing));
+    }
     false
 }
 
+/// Consumes the `)`, `]` or `}` closing `open`, noting where the group started if it is missing.
+fn consume_closing<'src>(parser: &mut Parser<'src>, open: Token<'src>, closing: TokenType, message: &str) {
+    let reported = parser.errors.len();
+    if !consume(parser, closing, message) && parser.errors.len() > reported {
+        let note = format!("unclosed '{}' opened at {}:{}", open.lexeme, open.line, open.column);
+        parser.errors[reported].notes.push(note);
+    }
+}
+
+fn match_token<'src>(parser: &mut Parser<'src>, token_type: TokenType) -> bool {
+    if parser.current.token_type != token_type {
+        return false;
+    }
+    advance(parser);
+    true
+}
+
 fn emit_byte<'src>(parser: &mut Parser<'src>, byte: u8) {
-    parser.chunk.write(byte, parser.previous.line);
+    let span = Span::new(parser.previous.offset, parser.previous.length);
+    emit_byte_at(parser, byte, span);
+}
+
+/// Emits a byte that runtime errors should attribute to `span` rather than the last token.
+fn emit_byte_at<'src>(parser: &mut Parser<'src>, byte: u8, span: Span) {
+    parser.chunk.write_spanned(byte, parser.previous.line, span);
 }
 
 fn emit_return<'src>(parser: &mut Parser<'src>) {
@@ -103,35 +213,281 @@ fn expression<'src>(parser: &mut Parser<'src>) {
 }
 
 fn number<'src>(parser: &mut Parser<'src>) {
-    let value: Value = if let Ok(num) = parser.previous.lexeme.parse() {
-        Value::Number(num)
-    } else {
-        error_at(parser, parser.previous, "Invalid number");
-        return;
+    // the scanner only produces number tokens it can represent
+    let value: Value = match number_value(parser.previous.lexeme) {
+        Ok(num) => Value::Number(num),
+        Err(message) => {
+            error_at(parser, parser.previous, message);
+            return;
+        }
     };
     emit_constant(parser, value);
 }
 
 fn string<'src>(parser: &mut Parser<'src>) {
-    let trimmed: String = parser.previous.lexeme.trim_matches('"').to_string();
-    emit_constant(parser, Value::String(trimmed));
+    // the rest of an interpolated string where an operand was expected, as in `"${1 +}"`
+    if parser.previous.lexeme.starts_with('}') {
+        error(parser, "Expect expression");
+        return;
+    }
+    let value = string_value(parser.previous.lexeme);
+    emit_constant(parser, Value::String(value));
+}
+
+/// Whether a token continues an interpolated string after the `}` closing an interpolation.
+fn continues_string(token: &Token) -> bool {
+    matches!(token.token_type, TokenType::String | TokenType::Interpolation) && token.lexeme.starts_with('}')
+}
+
+/// Compiles `"a ${x} b"` into `"a " + x + " b"`, where each interpolated value
+/// is first turned into a string by `Stringify`. Empty parts are left out and
+/// literals are folded.
+fn interpolation<'src>(parser: &mut Parser<'src>) {
+    let mut part = parser.previous;
+    if part.lexeme.starts_with('}') {
+        error(parser, "Expect expression");
+        return;
+    }
+    let triple = is_triple(part.lexeme);
+    let indent = if triple { closing_indent_ahead(parser) } else { None };
+    let start = parser.chunk.code.len();
+    let text = string_part_value(part.lexeme, triple, indent);
+    if !text.is_empty() {
+        emit_constant(parser, Value::String(text));
+    }
+    loop {
+        if continues_string(&parser.current) {
+            error_at_current(parser, "Expect expression");
+            return;
+        }
+        let value_start = parser.chunk.code.len();
+        let first = parser.current;
+        // the text so far waits on the stack
+        let held = usize::from(value_start != start);
+        parser.depth += held;
+        expression(parser);
+        parser.depth -= held;
+        let span = Span::new(first.offset, first.length).to(Span::new(parser.previous.offset, parser.previous.length));
+        match literal_at(parser, value_start, parser.chunk.code.len()) {
+            Some(value) => replace_with_literal(parser, value_start, Value::String(value.to_string())),
+            None => emit_byte_at(parser, OpCode::Stringify as u8, span),
+        }
+        concat(parser, start, value_start);
+
+        if !continues_string(&parser.current) {
+            let reported = parser.errors.len();
+            error_at_current(parser, "Expect '}' after interpolated expression");
+            if parser.errors.len() > reported {
+                let (line, column) = line_column(parser.source, part.offset + part.lexeme.len() - 2);
+                parser.errors[reported].notes.push(format!("unclosed '${{' opened at {}:{}", line, column));
+            }
+            return;
+        }
+        advance(parser);
+        part = parser.previous;
+        let text = string_part_value(part.lexeme, triple, indent);
+        if !text.is_empty() {
+            let text_start = parser.chunk.code.len();
+            emit_constant(parser, Value::String(text));
+            concat(parser, start, text_start);
+        }
+        if part.token_type == TokenType::String {
+            return;
+        }
+    }
+}
+
+/// Joins the string loaded by the code from `right_start` onto the one loaded
+/// from `start`, if there is one.
+fn concat<'src>(parser: &mut Parser<'src>, start: usize, right_start: usize) {
+    if start == right_start {
+        return;
+    }
+    let left = literal_at(parser, start, right_start);
+    let right = literal_at(parser, right_start, parser.chunk.code.len());
+    if let (Some(a), Some(b)) = (left, right) {
+        if let Ok(value) = a.add(&b) {
+            replace_with_literal(parser, start, value);
+            return;
+        }
+    }
+    emit_byte(parser, OpCode::Add as u8);
+}
+
+/// The indentation to remove from a `"""` string with interpolations, which
+/// depends on its last part, so the tokens up to there are scanned ahead.
+fn closing_indent_ahead<'src>(parser: &Parser<'src>) -> Option<&'src str> {
+    let mut scanner = parser.scanner.clone();
+    let mut token = parser.current;
+    // strings opened but not yet closed, counting the one being compiled
+    let mut depth = 1;
+    loop {
+        match token.token_type {
+            TokenType::Eof | TokenType::Error => return None,
+            TokenType::Interpolation if !token.lexeme.starts_with('}') => depth += 1,
+            TokenType::String if token.lexeme.starts_with('}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return closing_indent(token.lexeme, true);
+                }
+            }
+            _ => {}
+        }
+        token = scanner.scan_token();
+    }
+}
+
+fn identifier_constant<'src>(parser: &mut Parser<'src>, name: Token<'src>) -> u8 {
+    make_constant(parser, Value::String(name.lexeme.to_string()))
+}
+
+fn variable<'src>(parser: &mut Parser<'src>) {
+    let can_assign: bool = parser.can_assign;
+    let token = parser.previous;
+    let name: u8 = identifier_constant(parser, token);
+    if can_assign && match_token(parser, TokenType::Equal) {
+        expression(parser);
+        let span = Span::new(token.offset, token.length);
+        emit_byte_at(parser, OpCode::SetGlobal as u8, span);
+        emit_byte_at(parser, name, span);
+    } else {
+        emit_bytes(parser, OpCode::GetGlobal as u8, name);
+    }
+}
+
+fn call<'src>(parser: &mut Parser<'src>) {
+    let callee = Span::new(parser.infix_offset, 0);
+    // the callee stays below its arguments
+    parser.depth += 1;
+    let arg_count: u8 = argument_list(parser);
+    parser.depth -= 1;
+    // errors in the call point at the whole call, from the callee to the ')'
+    let span = callee.to(Span::new(parser.previous.offset, parser.previous.length));
+    emit_byte_at(parser, OpCode::Call as u8, span);
+    emit_byte_at(parser, arg_count, span);
+}
+
+fn argument_list<'src>(parser: &mut Parser<'src>) -> u8 {
+    let open = parser.previous;
+    let mut arg_count: usize = 0;
+    if parser.current.token_type != TokenType::RightParen {
+        loop {
+            expression(parser);
+            parser.depth += 1;
+            if arg_count == u8::MAX as usize {
+                error(parser, "Can't have more than 255 arguments");
+            }
+            arg_count += 1;
+            if !match_token(parser, TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    consume_closing(parser, open, TokenType::RightParen, "Expect ')' after arguments");
+    parser.depth -= arg_count;
+    arg_count.min(u8::MAX as usize) as u8
 }
 
 fn grouping<'src>(parser: &mut Parser<'src>) {
+    let open = parser.previous;
+    expression(parser);
+    consume_closing(parser, open, TokenType::RightParen, "Expect ')' after expression");
+}
+
+fn list<'src>(parser: &mut Parser<'src>) {
+    let open = parser.previous;
+    let mut count: usize = 0;
+    while parser.current.token_type != TokenType::RightBracket {
+        expression(parser);
+        parser.depth += 1;
+        if count == u8::MAX as usize {
+            error(parser, "Can't have more than 255 items in a list literal");
+        }
+        count += 1;
+        // a trailing comma is allowed
+        if !match_token(parser, TokenType::Comma) {
+            break;
+        }
+    }
+    consume_closing(parser, open, TokenType::RightBracket, "Expect ']' after list items");
+    parser.depth -= count;
+    let span = Span::new(open.offset, open.length).to(Span::new(parser.previous.offset, parser.previous.length));
+    emit_byte_at(parser, OpCode::BuildList as u8, span);
+    emit_byte_at(parser, count.min(u8::MAX as usize) as u8, span);
+}
+
+/// A map literal. Since a `{` only reaches the expression parser where an
+/// operand is expected, it can't be a block here.
+fn map<'src>(parser: &mut Parser<'src>) {
+    let open = parser.previous;
+    let mut count: usize = 0;
+    while parser.current.token_type != TokenType::RightBrace {
+        expression(parser);
+        parser.depth += 1;
+        consume(parser, TokenType::Colon, "Expect ':' after map key");
+        expression(parser);
+        parser.depth += 1;
+        if count == u8::MAX as usize {
+            error(parser, "Can't have more than 255 entries in a map literal");
+        }
+        count += 1;
+        // a trailing comma is allowed
+        if !match_token(parser, TokenType::Comma) {
+            break;
+        }
+    }
+    consume_closing(parser, open, TokenType::RightBrace, "Expect '}' after map entries");
+    parser.depth -= count * 2;
+    let span = Span::new(open.offset, open.length).to(Span::new(parser.previous.offset, parser.previous.length));
+    emit_byte_at(parser, OpCode::BuildMap as u8, span);
+    emit_byte_at(parser, count.min(u8::MAX as usize) as u8, span);
+}
+
+fn index<'src>(parser: &mut Parser<'src>) {
+    let can_assign: bool = parser.can_assign;
+    let open = parser.previous;
+    let target = Span::new(parser.infix_offset, 0);
+    parser.depth += 1;
     expression(parser);
-    consume(parser, TokenType::RightParen, "Expect ')' after expression");
+    parser.depth -= 1;
+    consume_closing(parser, open, TokenType::RightBracket, "Expect ']' after index");
+    // errors point at the whole `target[index]`
+    let span = target.to(Span::new(parser.previous.offset, parser.previous.length));
+    if can_assign && match_token(parser, TokenType::Equal) {
+        parser.depth += 2;
+        expression(parser);
+        parser.depth -= 2;
+        emit_byte_at(parser, OpCode::IndexSet as u8, span);
+    } else {
+        emit_byte_at(parser, OpCode::IndexGet as u8, span);
+    }
 }
 
 fn unary<'src>(parser: &mut Parser<'src>) {
     let operator_type: TokenType = parser.previous.token_type;
+    let operator = Span::new(parser.previous.offset, parser.previous.length);
+    let operand_start: usize = parser.chunk.code.len();
 
     // Compile the operand
     parse_precedence(parser, Precedence::Unary);
 
+    // fold the operator into the operand if it is a literal
+    if let Some(operand) = literal_at(parser, operand_start, parser.chunk.code.len()) {
+        let folded = match operator_type {
+            TokenType::Minus => operand.negate(),
+            TokenType::Bang => operand.not(),
+            _ => unreachable!(),
+        };
+        if let Ok(value) = folded {
+            replace_with_literal(parser, operand_start, value);
+            return;
+        }
+    }
+
     // emit the operator instruction
     match operator_type {
-        TokenType::Minus => emit_byte(parser, OpCode::Negate as u8),
-        TokenType::Bang => emit_byte(parser, OpCode::Not as u8),
+        TokenType::Minus => emit_byte_at(parser, OpCode::Negate as u8, operator),
+        TokenType::Bang => emit_byte_at(parser, OpCode::Not as u8, operator),
         _ => unreachable!(),
     }
 }
@@ -139,23 +495,56 @@ fn unary<'src>(parser: &mut Parser<'src>) {
 fn binary<'src>(parser: &mut Parser<'src>) {
     // Retrieve the type of the operator from the previous token
     let operator_type: TokenType = parser.previous.token_type;
+    let operator = Span::new(parser.previous.offset, parser.previous.length);
+    let left_start: usize = parser.infix_start;
+    let right_start: usize = parser.chunk.code.len();
     // Get the parsing rule associated with the operator type
     let rule: ParseRule = get_rule(operator_type);
     // Raise the precedence level to parse the right operand and keep left associativity
+    // the left operand waits on the stack meanwhile
+    parser.depth += 1;
     parse_precedence(parser, rule.precedence.increment());
+    parser.depth -= 1;
 
-    match operator_type {
-        TokenType::Plus => emit_byte(parser, OpCode::Add as u8),
-        TokenType::Minus => emit_byte(parser, OpCode::Subtract as u8),
-        TokenType::Star => emit_byte(parser, OpCode::Multiply as u8),
-        TokenType::Slash => emit_byte(parser, OpCode::Divide as u8),
-        TokenType::EqualEqual => emit_byte(parser, OpCode::Equal as u8),
-        TokenType::BangEqual => emit_bytes(parser, OpCode::Equal as u8, OpCode::Not as u8),
-        TokenType::Greater => emit_byte(parser, OpCode::Greater as u8),
-        TokenType::GreaterEqual => emit_bytes(parser, OpCode::Less as u8, OpCode::Not as u8),
-        TokenType::Less => emit_byte(parser, OpCode::Less as u8),
-        TokenType::LessEqual => emit_bytes(parser, OpCode::Greater as u8, OpCode::Not as u8),
+    // fold the operation if both operands are literals
+    // errors are left for the VM to report at runtime
+    let left = literal_at(parser, left_start, right_start);
+    let right = literal_at(parser, right_start, parser.chunk.code.len());
+    if let (Some(a), Some(b)) = (left, right) {
+        let folded = match operator_type {
+            TokenType::Plus => a.add(&b),
+            TokenType::Minus => a.subtract(&b),
+            TokenType::Star => a.multiply(&b),
+            TokenType::Slash => a.divide(&b),
+            TokenType::EqualEqual => Ok(a.equal(&b)),
+            TokenType::BangEqual => a.equal(&b).not(),
+            TokenType::Greater => Ok(a.greater(&b)),
+            TokenType::GreaterEqual => a.less(&b).not(),
+            TokenType::Less => Ok(a.less(&b)),
+            TokenType::LessEqual => a.greater(&b).not(),
+            _ => unreachable!(),
+        };
+        if let Ok(value) = folded {
+            replace_with_literal(parser, left_start, value);
+            return;
+        }
+    }
+
+    let opcodes: &[OpCode] = match operator_type {
+        TokenType::Plus => &[OpCode::Add],
+        TokenType::Minus => &[OpCode::Subtract],
+        TokenType::Star => &[OpCode::Multiply],
+        TokenType::Slash => &[OpCode::Divide],
+        TokenType::EqualEqual => &[OpCode::Equal],
+        TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+        TokenType::Greater => &[OpCode::Greater],
+        TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+        TokenType::Less => &[OpCode::Less],
+        TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
         _ => unreachable!(),
+    };
+    for &opcode in opcodes {
+        emit_byte_at(parser, opcode as u8, operator);
     }
 }
 
@@ -168,11 +557,58 @@ fn literal<'src>(parser: &mut Parser<'src>) {
     }
 }
 
+/// Returns the value of the code between `start` and `end` if it is a single literal instruction.
+fn literal_at<'src>(parser: &Parser<'src>, start: usize, end: usize) -> Option<Value> {
+    let code = &parser.chunk.code[start..end];
+    match code {
+        [op, idx] if *op == OpCode::Constant as u8 => Some(parser.chunk.get_const(*idx as usize)),
+        [op] if *op == OpCode::True as u8 => Some(Value::Bool(true)),
+        [op] if *op == OpCode::False as u8 => Some(Value::Bool(false)),
+        [op] if *op == OpCode::Nil as u8 => Some(Value::Nil),
+        _ => None,
+    }
+}
+
+/// Discards the code emitted since `start` and loads `value` in its place.
+fn replace_with_literal<'src>(parser: &mut Parser<'src>, start: usize, value: Value) {
+    // the discarded literals were the most recently added constants
+    let mut offset = start;
+    while offset < parser.chunk.code.len() {
+        if parser.chunk.code[offset] == OpCode::Constant as u8 {
+            let idx = parser.chunk.code[offset + 1] as usize;
+            parser.chunk.constants.truncate(idx);
+            break;
+        }
+        offset += 1;
+    }
+    parser.chunk.code.truncate(start);
+    parser.chunk.lines.truncate(start);
+    parser.chunk.spans.truncate(start);
+
+    match value {
+        Value::Bool(true) => emit_byte(parser, OpCode::True as u8),
+        Value::Bool(false) => emit_byte(parser, OpCode::False as u8),
+        Value::Nil => emit_byte(parser, OpCode::Nil as u8),
+        value => emit_constant(parser, value),
+    }
+}
+
 fn parse_precedence<'src>(parser: &mut Parser<'src>, precedence: Precedence) {
+    // the VM has no room for the value this expression leaves on the stack
+    if parser.depth >= STACK_MAX {
+        if let Some(error) = error_at_current(parser, "Too many values on the stack at once") {
+            error.help = Some(format!("the stack holds {} values; split the expression up", STACK_MAX));
+        }
+    }
+    let expression_start: usize = parser.chunk.code.len();
+    let expression_offset: usize = parser.current.offset;
     // Advance to the next token
     advance(parser);
     // Get the ParseRule for the previous token
     let mut rule: ParseRule = get_rule(parser.previous.token_type);
+    // Only the lowest precedence expression can be assigned to
+    let can_assign: bool = precedence <= Precedence::Assignment;
+    parser.can_assign = can_assign;
     // Call the prefix function if it exists
     match rule.prefix {
         Some(prefix) => prefix(parser),
@@ -186,6 +622,9 @@ fn parse_precedence<'src>(parser: &mut Parser<'src>, precedence: Precedence) {
     while precedence <= get_rule(parser.current.token_type).precedence {
         advance(parser);
         rule = get_rule(parser.previous.token_type);
+        parser.infix_start = expression_start;
+        parser.infix_offset = expression_offset;
+        parser.can_assign = can_assign;
         match rule.infix {
             Some(infix) => infix(parser),
             None => {
@@ -194,6 +633,13 @@ fn parse_precedence<'src>(parser: &mut Parser<'src>, precedence: Precedence) {
             }
         }
     }
+
+    // An '=' left over means the left hand side was not a variable
+    if can_assign && match_token(parser, TokenType::Equal) {
+        if let Some(error) = error(parser, "Invalid assignment target") {
+            error.help = Some("only variables, list items and map entries can be assigned to".to_string());
+        }
+    }
 }
 
 fn advance<'src>(parser: &mut Parser<'src>) {
@@ -204,12 +650,28 @@ fn advance<'src>(parser: &mut Parser<'src>) {
         if parser.current.token_type != TokenType::Error {
             break;
         }
-        error_at_current(parser, &parser.current.lexeme);
+        let help = match parser.current.lexeme {
+            "Unterminated string." => "add a closing '\"' where the string should end",
+            "Unterminated multi-line string." => "add a closing '\"\"\"' where the string should end",
+            "Invalid escape sequence." => concat!(
+                "valid escapes are \\n, \\t, \\r, \\0, \\\\, \\\", \\$ and \\u{...}; ",
+                "use a raw string r\"...\" to keep backslashes"
+            ),
+            _ => "",
+        };
+        if let Some(error) = error_at_current(parser, parser.current.lexeme) {
+            if !help.is_empty() {
+                error.help = Some(help.to_string());
+            }
+        }
     }
 }
 
 fn end_compiler<'src>(parser: &mut Parser<'src>) {
     emit_return(parser);
+    if !parser.had_error {
+        optimize(&mut parser.chunk);
+    }
     #[cfg(feature = "debug")]
     {
         if !parser.had_error {
@@ -236,13 +698,20 @@ enum Precedence {
 
 impl Precedence {
     pub fn increment(self) -> Self {
-        Precedence::from((self as u8) + 1)
-    }
-}
-
-impl From<u8> for Precedence {
-    fn from(value: u8) -> Self {
-        unsafe { std::mem::transmute(value) }
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            // nothing binds tighter than a primary expression
+            Precedence::Primary => Precedence::Primary,
+        }
     }
 }
 
@@ -259,8 +728,8 @@ fn get_rule(token_type: TokenType) -> ParseRule {
     match token_type {
         TokenType::LeftParen => ParseRule {
             prefix: Some(grouping),
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(call),
+            precedence: Precedence::Call,
         },
         TokenType::RightParen => ParseRule {
             prefix: None,
@@ -268,7 +737,7 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             precedence: Precedence::None,
         },
         TokenType::LeftBrace => ParseRule {
-            prefix: None,
+            prefix: Some(map),
             infix: None,
             precedence: Precedence::None,
         },
@@ -277,6 +746,21 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             infix: None,
             precedence: Precedence::None,
         },
+        TokenType::LeftBracket => ParseRule {
+            prefix: Some(list),
+            infix: Some(index),
+            precedence: Precedence::Call,
+        },
+        TokenType::RightBracket => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::Colon => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
         TokenType::Comma => ParseRule {
             prefix: None,
             infix: None,
@@ -353,7 +837,7 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             precedence: Precedence::Comparison,
         },
         TokenType::Identifier => ParseRule {
-            prefix: None,
+            prefix: Some(variable),
             infix: None,
             precedence: Precedence::None,
         },
@@ -362,6 +846,11 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             infix: None,
             precedence: Precedence::None,
         },
+        TokenType::Interpolation => ParseRule {
+            prefix: Some(interpolation),
+            infix: None,
+            precedence: Precedence::None,
+        },
         TokenType::Number => ParseRule {
             prefix: Some(number),
             infix: None,
@@ -491,14 +980,148 @@ mod tests {
         // ensure compilation is successful
         assert!(result.is_ok());
         let chunk = result.unwrap();
-        // 1 and 2 are constants
-        assert_eq!(chunk.constants.len(), 2);
-        // there should be 6 bytes pushed to the chunk:
+        // 1 + 2 is folded into a single constant
+        assert_eq!(chunk.constants, vec![Value::Number(3.0)]);
+        // there should be 3 bytes pushed to the chunk:
         // 1. push const
-        // 2. push const
-        // 3. add
-        // 4. return
+        // 2. return
+        assert_eq!(chunk.code.len(), 3);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let chunk = compile("-(1 + 2 * 3) - 4").unwrap();
+        assert_eq!(chunk.constants, vec![Value::Number(-11.0)]);
+        assert_eq!(chunk.code, vec![OpCode::Constant as u8, 0, OpCode::Return as u8]);
+
+        let chunk = compile("\"a\" + \"b\" + \"c\"").unwrap();
+        assert_eq!(chunk.constants, vec![Value::String("abc".to_string())]);
+
+        let chunk = compile("!(1 >= 2)").unwrap();
+        assert_eq!(chunk.code, vec![OpCode::True as u8, OpCode::Return as u8]);
+        assert!(chunk.constants.is_empty());
+
+        let chunk = compile("nil != false").unwrap();
+        assert_eq!(chunk.code, vec![OpCode::True as u8, OpCode::Return as u8]);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let chunk = compile("\"a ${1 + 1} ${nil}${\"${true}\"}!\"").unwrap();
+        assert_eq!(chunk.constants, vec![Value::String("a 2 niltrue!".to_string())]);
+        assert_eq!(chunk.code, vec![OpCode::Constant as u8, 0, OpCode::Return as u8]);
+
+        // empty parts are left out, so `"${x}"` only stringifies `x`
+        let chunk = compile("\"${x}\"").unwrap();
+        assert_eq!(chunk.code, vec![OpCode::GetGlobal as u8, 0, OpCode::Stringify as u8, OpCode::Return as u8]);
+
+        let errors = compile("\"a ${x y}\"").unwrap_err();
+        assert_eq!(errors[0].message, "Expect '}' after interpolated expression");
+        assert_eq!(errors[0].column, 8);
+        assert_eq!(errors[0].notes, vec!["unclosed '${' opened at 1:4".to_string()]);
+        assert_eq!(compile("\"${}\"").unwrap_err()[0].message, "Expect expression");
+        assert_eq!(compile("\"${1 +}\"").unwrap_err()[0].message, "Expect expression");
+    }
+
+    #[test]
+    fn test_constant_folding_leaves_errors_for_runtime() {
+        let chunk = compile("\"a\" - 1").unwrap();
+        assert_eq!(chunk.constants.len(), 2);
         assert_eq!(chunk.code.len(), 6);
+        assert_eq!(chunk.code[4], OpCode::Subtract as u8);
+
+        let chunk = compile("-\"a\"").unwrap();
+        assert_eq!(chunk.code[2], OpCode::Negate as u8);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert!(compile("1 +").is_err());
+        assert!(compile("1 2").is_err());
+        assert!(compile("(1").is_err());
+
+        let errors = compile("1\n2").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "[line 2] Error at '2': Expect end of expression");
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        let map = |entries: usize| {
+            let entries: Vec<String> = (0..entries).map(|i| format!("{}: nil", i)).collect();
+            format!("{{{}}}", entries.join(", "))
+        };
+        // 128 entries fill the stack exactly
+        let chunk = compile(&map(128)).unwrap();
+        assert_eq!(crate::verifier::verify(&chunk), Ok(()));
+
+        let errors = compile(&map(130)).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '128': Too many values on the stack at once");
+        // the left operand takes a slot too
+        assert!(compile(&format!("x + {}", map(128))).is_err());
+
+        // nested literals add up
+        let nils = |count: usize| vec!["nil"; count].join(", ");
+        let source = format!("[{}, [{}]]", nils(200), nils(200));
+        let errors = compile(&source).unwrap_err();
+        assert_eq!(errors[0].message, "Too many values on the stack at once");
+        // at the 57th inner item, which would be the 257th value
+        let inner = source.rfind('[').unwrap();
+        assert_eq!(errors[0].column, inner + 1 + 56 * 5 + 1);
+
+        // as do nested calls, where each callee takes a slot as well
+        assert!(compile(&format!("len({}, len({}))", nils(200), nils(54))).is_ok());
+        let errors = compile(&format!("len({}, len({}))", nils(200), nils(55))).unwrap_err();
+        assert_eq!(errors[0].message, "Too many values on the stack at once");
+    }
+
+    #[test]
+    fn test_error_spans_and_hints() {
+        let errors = compile("f(1,\n  \"two").unwrap_err();
+        assert_eq!((errors[0].line, errors[0].column, errors[0].span), (2, 3, Span::new(7, 4)));
+        assert_eq!(errors[0].help.as_deref(), Some("add a closing '\"' where the string should end"));
+
+        let errors = compile("x = add(1, 2 3)").unwrap_err();
+        assert_eq!((errors[0].column, errors[0].span), (14, Span::new(13, 1)));
+        assert_eq!(errors[0].notes, vec!["unclosed '(' opened at 1:8"]);
+        assert_eq!(errors[0].help.as_deref(), Some("did you forget a ')'?"));
+
+        // runtime errors in a call point at the whole call
+        let chunk = compile("x = add(1, 2)").unwrap();
+        let call = chunk.code.iter().position(|&byte| byte == OpCode::Call as u8).unwrap();
+        assert_eq!(chunk.spans[call], Span::new(4, 9));
+        assert_eq!(chunk.spans.len(), chunk.code.len());
+    }
+
+    #[test]
+    fn test_globals_and_calls() {
+        let chunk = compile("total = add(x, 1)").unwrap();
+        let opcodes: Vec<OpCode> = crate::debug::disassemble(&chunk)
+            .unwrap()
+            .iter()
+            .map(|instruction| instruction.opcode)
+            .collect();
+        assert_eq!(
+            opcodes,
+            vec![
+                OpCode::GetGlobal,
+                OpCode::GetGlobal,
+                OpCode::Constant,
+                OpCode::Call,
+                OpCode::SetGlobal,
+                OpCode::Return,
+            ]
+        );
+        assert_eq!(chunk.code[7], 2);
+        assert!(compile("1 + a = 2").is_err());
+        assert!(compile("f(1, 2").is_err());
+    }
+
+    #[test]
+    fn test_return_line() {
+        let chunk = compile("-\"a\"\n\n").unwrap();
+        assert_eq!(chunk.lines, vec![1, 1, 1, 1]);
     }
 
     #[test]
@@ -520,7 +1143,7 @@ mod tests {
     fn test_get_rule() {
         let rule: ParseRule = get_rule(TokenType::Plus);
         assert_eq!(rule.precedence, Precedence::Term);
-        assert_eq!(rule.prefix, None);
+        assert!(rule.prefix.is_none());
         assert!(rule.infix.is_some());
     }
 }
\ No newline at end of file
@@ -0,0 +1,86 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::chunk::Chunk;
+
+/// Line coverage for a script, built from the line table of each chunk it runs.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    /// Instructions executed per line, for every line that has code.
+    pub lines: BTreeMap<usize, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers every line with code in the chunk so unexecuted lines are reported too.
+    pub fn instrument(&mut self, chunk: &Chunk) {
+        for &line in &chunk.lines {
+            self.lines.entry(line).or_insert(0);
+        }
+    }
+
+    pub fn hit(&mut self, line: usize) {
+        *self.lines.entry(line).or_insert(0) += 1;
+    }
+
+    pub fn covered(&self) -> usize {
+        self.lines.values().filter(|&&hits| hits > 0).count()
+    }
+
+    pub fn uncovered_lines(&self) -> Vec<usize> {
+        self.lines.iter().filter(|(_, &hits)| hits == 0).map(|(&line, _)| line).collect()
+    }
+
+    /// Renders the coverage as an lcov tracefile for `path`.
+    pub fn to_lcov(&self, path: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", path).unwrap();
+        for (line, hits) in &self.lines {
+            writeln!(out, "DA:{},{}", line, hits).unwrap();
+        }
+        writeln!(out, "LF:{}", self.lines.len()).unwrap();
+        writeln!(out, "LH:{}", self.covered()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+
+    /// A one line summary followed by the lines that never ran.
+    pub fn summary(&self, path: &str) -> String {
+        let total = self.lines.len();
+        let percent = if total == 0 { 100.0 } else { self.covered() as f64 * 100.0 / total as f64 };
+        let mut out = format!("{}: {}/{} lines covered ({:.1}%)\n", path, self.covered(), total, percent);
+        let uncovered = self.uncovered_lines();
+        if !uncovered.is_empty() {
+            let lines: Vec<String> = uncovered.iter().map(usize::to_string).collect();
+            writeln!(out, "  not covered: {}", lines.join(", ")).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::VM;
+
+    #[test]
+    fn test_runtime_error_leaves_lines_uncovered() {
+        let mut vm = VM::default();
+        vm.set_stderr(std::io::sink());
+        vm.enable_coverage();
+        vm.interpret("-\"a\"\n+\n1");
+        let coverage = vm.coverage().unwrap();
+        assert_eq!(coverage.lines.get(&1), Some(&2));
+        assert_eq!(coverage.uncovered_lines(), vec![3]);
+        assert_eq!(
+            coverage.to_lcov("test.lox"),
+            "TN:\nSF:test.lox\nDA:1,2\nDA:3,0\nLF:2\nLH:1\nend_of_record\n"
+        );
+        assert_eq!(
+            coverage.summary("test.lox"),
+            "test.lox: 1/2 lines covered (50.0%)\n  not covered: 3\n"
+        );
+    }
+}
@@ -0,0 +1,466 @@
+//! A Debug Adapter Protocol server, spoken over stdin/stdout.
+//!
+//! The adapter debugs a single program with a single thread. It uses the same
+//! `Content-Length` framing as the language server and drives the VM through
+//! the same `DebugHook` and `Stepper` as the command-line debugger: requests
+//! are read on the VM's thread, from inside the hook while the program is paused.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use crate::compiler::compile;
+use crate::debugger::{Step, StopReason, Stepper};
+use crate::json::Json;
+use crate::lsp::{read_message, write_message};
+use crate::vm::{DebugHook, InterpretResult, OutputBuffer, VM};
+
+const THREAD_ID: usize = 1;
+const STACK_REFERENCE: usize = 1;
+const GLOBALS_REFERENCE: usize = 2;
+
+/// What the request loop should do after handling a request.
+enum Control {
+    Wait,
+    Start,
+    Resume(Step),
+    Disconnect,
+}
+
+struct Program {
+    path: String,
+    source: String,
+}
+
+struct Session {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    seq: usize,
+    stepper: Stepper,
+    program: Option<Program>,
+    // the program's output, forwarded as `output` events
+    stdout: OutputBuffer,
+    stderr: OutputBuffer,
+    disconnected: bool,
+    // an I/O error hit inside the hook, where it cannot be returned
+    error: Option<io::Error>,
+}
+
+impl Session {
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq", self.seq.into()));
+        write_message(&mut self.output, &Json::object(fields))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![("type", "event".into()), ("event", event.into()), ("body", body)])
+    }
+
+    /// The request, or `None` after answering a message that was not valid JSON.
+    fn parsed(&mut self, request: Result<Json, String>) -> io::Result<Option<Json>> {
+        match request {
+            Ok(request) => Ok(Some(request)),
+            Err(msg) => {
+                self.respond(&Json::Null, Err(format!("Parse error: {}", msg)))?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut fields = vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", result.is_ok().into()),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+        match result {
+            Ok(body) => fields.push(("body", body)),
+            Err(message) => fields.push(("message", message.into())),
+        }
+        self.send(fields)
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        for (category, buffer) in [("stdout", self.stdout.clone()), ("stderr", self.stderr.clone())] {
+            let output = buffer.contents();
+            if !output.is_empty() {
+                buffer.clear();
+                self.event("output", Json::object(vec![("category", category.into()), ("output", output.into())]))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, vm: Option<&mut VM>, request: &Json) -> io::Result<Control> {
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let mut control = Control::Wait;
+        let result = match (command, vm) {
+            ("initialize", _) => {
+                self.respond(request, Ok(Json::object(vec![("supportsConfigurationDoneRequest", true.into())])))?;
+                return self.event("initialized", Json::Object(Vec::new())).map(|_| Control::Wait);
+            }
+            ("launch", _) => self.launch(arguments),
+            ("setBreakpoints", _) => Ok(self.set_breakpoints(arguments)),
+            ("configurationDone", _) => {
+                control = Control::Start;
+                Ok(Json::Null)
+            }
+            ("threads", _) => Ok(Json::object(vec![(
+                "threads",
+                Json::Array(vec![Json::object(vec![("id", THREAD_ID.into()), ("name", "main".into())])]),
+            )])),
+            ("disconnect" | "terminate", _) => {
+                control = Control::Disconnect;
+                Ok(Json::Null)
+            }
+            (_, None) if matches!(command, "continue" | "next" | "stepIn" | "stepOut") => {
+                Err("The program is not paused".to_string())
+            }
+            ("continue", Some(_)) => {
+                control = Control::Resume(Step::Continue);
+                Ok(Json::object(vec![("allThreadsContinued", true.into())]))
+            }
+            ("next" | "stepIn" | "stepOut", Some(_)) => {
+                control = Control::Resume(match command {
+                    "next" => Step::Over,
+                    "stepIn" => Step::Into,
+                    _ => Step::Out,
+                });
+                Ok(Json::Null)
+            }
+            ("stackTrace" | "scopes" | "variables" | "evaluate", Some(vm)) => self.inspect(vm, command, arguments),
+            ("stackTrace" | "scopes" | "variables" | "evaluate", None) => Err("The program is not paused".to_string()),
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+        self.respond(request, result)?;
+        Ok(control)
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments.get("program").and_then(Json::as_str).ok_or("Missing 'program'")?;
+        let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        if arguments.get("stopOnEntry").and_then(Json::as_bool) == Some(true) {
+            let breakpoints = std::mem::take(&mut self.stepper.breakpoints);
+            self.stepper = Stepper::new(true);
+            self.stepper.breakpoints = breakpoints;
+        }
+        self.program = Some(Program { path: path.to_string(), source });
+        Ok(Json::Null)
+    }
+
+    /// Replaces the breakpoints, marking those on lines without code as unverified.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let lines: Vec<usize> = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_f64))
+            .map(|line| line as usize)
+            .collect();
+        let code_lines =
+            self.program.as_ref().and_then(|program| compile(&program.source).ok()).map(|chunk| chunk.lines);
+        self.stepper.breakpoints = lines.iter().copied().collect();
+        let breakpoints = lines
+            .iter()
+            .map(|&line| {
+                let verified = code_lines.as_ref().is_none_or(|code_lines| code_lines.contains(&line));
+                Json::object(vec![("verified", verified.into()), ("line", line.into())])
+            })
+            .collect();
+        Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+    }
+
+    fn inspect(&mut self, vm: &mut VM, command: &str, arguments: &Json) -> Result<Json, String> {
+        match command {
+            "stackTrace" => {
+                let source = self.program.as_ref().map_or(Json::Null, |program| {
+                    let name = std::path::Path::new(&program.path).file_name().map(|name| name.to_string_lossy());
+                    let name = name.unwrap_or_default().to_string();
+                    Json::object(vec![("name", name.into()), ("path", program.path.as_str().into())])
+                });
+                let frames: Vec<Json> = vm
+                    .stack_trace()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, (name, line))| {
+                        Json::object(vec![
+                            ("id", id.into()),
+                            ("name", name.into()),
+                            ("source", source.clone()),
+                            ("line", line.into()),
+                            ("column", 1.0.into()),
+                        ])
+                    })
+                    .collect();
+                let total = frames.len();
+                Ok(Json::object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", total.into())]))
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: usize| {
+                    Json::object(vec![
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                Ok(Json::object(vec![(
+                    "scopes",
+                    Json::Array(vec![scope("Stack", STACK_REFERENCE), scope("Globals", GLOBALS_REFERENCE)]),
+                )]))
+            }
+            "variables" => {
+                let variable = |name: String, value: String| {
+                    Json::object(vec![
+                        ("name", name.into()),
+                        ("value", value.into()),
+                        ("variablesReference", 0.0.into()),
+                    ])
+                };
+                let reference = arguments.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+                let variables: Vec<Json> = match reference {
+                    STACK_REFERENCE => vm
+                        .stack_values()
+                        .iter()
+                        .enumerate()
+                        .map(|(slot, value)| variable(format!("[{}]", slot), value.to_string()))
+                        .collect(),
+                    GLOBALS_REFERENCE => vm
+                        .globals()
+                        .into_iter()
+                        .map(|(name, value)| variable(name.to_string(), value.to_string()))
+                        .collect(),
+                    _ => return Err(format!("Unknown variables reference {}", reference)),
+                };
+                Ok(Json::object(vec![("variables", Json::Array(variables))]))
+            }
+            _ => {
+                let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or("");
+                let value = vm.evaluate(expression)?;
+                Ok(Json::object(vec![("result", value.to_string().into()), ("variablesReference", 0.0.into())]))
+            }
+        }
+    }
+
+    /// Runs the launched program to completion, pausing through the hook.
+    fn start(session: &Rc<RefCell<Session>>) -> io::Result<()> {
+        let Some(source) = session.borrow().program.as_ref().map(|program| program.source.clone()) else {
+            let mut session = session.borrow_mut();
+            let body = Json::object(vec![("category", "stderr".into()), ("output", "No program was launched\n".into())]);
+            session.event("output", body)?;
+            return session.event("terminated", Json::Object(Vec::new()));
+        };
+        let mut vm = VM::default();
+        vm.set_stdout(session.borrow().stdout.clone());
+        vm.set_stderr(session.borrow().stderr.clone());
+        vm.set_debug_hook(Hook(session.clone()));
+        let result = vm.interpret(&source);
+
+        let mut session = session.borrow_mut();
+        if let Some(err) = session.error.take() {
+            return Err(err);
+        }
+        if session.disconnected {
+            return Ok(());
+        }
+        session.flush_output()?;
+        let code: usize = match result {
+            InterpretResult::Ok => 0,
+            InterpretResult::CompileError => 65,
+            InterpretResult::RuntimeError | InterpretResult::Aborted(_) => 70,
+        };
+        session.event("exited", Json::object(vec![("exitCode", code.into())]))?;
+        session.event("terminated", Json::Object(Vec::new()))
+    }
+}
+
+struct Hook(Rc<RefCell<Session>>);
+
+impl Hook {
+    /// Reports the stop and serves requests until one resumes the program.
+    fn pause(&self, vm: &mut VM, reason: StopReason, depth: usize) -> io::Result<bool> {
+        let mut session = self.0.borrow_mut();
+        session.flush_output()?;
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+        };
+        let body = Json::object(vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]);
+        session.event("stopped", body)?;
+        loop {
+            let Some(request) = read_message(&mut session.input)? else {
+                session.disconnected = true;
+                return Ok(false);
+            };
+            let Some(request) = session.parsed(request)? else {
+                continue;
+            };
+            match session.handle(Some(&mut *vm), &request)? {
+                Control::Resume(step) => {
+                    session.stepper.resume(step, depth);
+                    return Ok(true);
+                }
+                Control::Disconnect => {
+                    session.disconnected = true;
+                    return Ok(false);
+                }
+                Control::Wait | Control::Start => {}
+            }
+        }
+    }
+}
+
+impl DebugHook for Hook {
+    fn on_line(&mut self, vm: &mut VM, line: usize) -> bool {
+        let depth = vm.call_depth();
+        let Some(reason) = self.0.borrow_mut().stepper.should_pause(line, depth) else {
+            return true;
+        };
+        match self.pause(vm, reason, depth) {
+            Ok(keep_running) => keep_running,
+            Err(err) => {
+                self.0.borrow_mut().error = Some(err);
+                false
+            }
+        }
+    }
+}
+
+/// Serves one debug session until the client disconnects or the input ends.
+pub fn run(input: impl BufRead + 'static, output: impl Write + 'static) -> io::Result<()> {
+    let session = Rc::new(RefCell::new(Session {
+        input: Box::new(input),
+        output: Box::new(output),
+        seq: 0,
+        stepper: Stepper::default(),
+        program: None,
+        stdout: OutputBuffer::new(),
+        stderr: OutputBuffer::new(),
+        disconnected: false,
+        error: None,
+    }));
+    loop {
+        let Some(request) = read_message(&mut session.borrow_mut().input)? else {
+            return Ok(());
+        };
+        let Some(request) = session.borrow_mut().parsed(request)? else {
+            continue;
+        };
+        let control = session.borrow_mut().handle(None, &request)?;
+        match control {
+            Control::Start => Session::start(&session)?,
+            Control::Disconnect => return Ok(()),
+            Control::Wait | Control::Resume(_) => {}
+        }
+        if session.borrow().disconnected {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays the `->` requests of a transcript and checks the server sends
+    /// exactly the `<-` messages. `$PROGRAM` and `$NAME` stand for the program's
+    /// path and file name.
+    fn replay(program: &str, transcript: &str) {
+        let path = std::env::temp_dir().join(format!("lox-dap-test-{}.lox", std::process::id()));
+        std::fs::write(&path, program).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let name = path.rsplit('/').next().unwrap();
+        let transcript = transcript.replace("$PROGRAM", &path).replace("$NAME", name);
+
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for line in transcript.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match line.split_at(3) {
+                ("-> ", message) => write!(input, "Content-Length: {}\r\n\r\n{}", message.len(), message).unwrap(),
+                ("<- ", message) => expected.push(Json::parse(message).unwrap()),
+                _ => panic!("Bad transcript line: {}", line),
+            }
+        }
+        let output = OutputBuffer::new();
+        run(io::Cursor::new(input), output.clone()).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let output = output.contents();
+        let mut output = output.as_bytes();
+        let mut received = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            received.push(message.unwrap());
+        }
+        for (index, (received, expected)) in received.iter().zip(&expected).enumerate() {
+            assert_eq!(received, expected, "message {} differs", index);
+        }
+        assert_eq!(received.len(), expected.len());
+    }
+
+    #[test]
+    fn test_breakpoint_session() {
+        // folding leaves "ab" on line 2; the negation fails at runtime on line 3
+        replay(
+            "\"a\"\n  + \"b\"\n  + -\"c\"\n",
+            r#"
+-> {"seq":1,"type":"request","command":"initialize","arguments":{"adapterID":"lox"}}
+<- {"seq":1,"type":"response","request_seq":1,"success":true,"command":"initialize","body":{"supportsConfigurationDoneRequest":true}}
+<- {"seq":2,"type":"event","event":"initialized","body":{}}
+-> {"seq":2,"type":"request","command":"launch","arguments":{"program":"$PROGRAM","stopOnEntry":true}}
+<- {"seq":3,"type":"response","request_seq":2,"success":true,"command":"launch","body":null}
+-> {"seq":3,"type":"request","command":"setBreakpoints","arguments":{"source":{"path":"$PROGRAM"},"breakpoints":[{"line":3},{"line":9}]}}
+<- {"seq":4,"type":"response","request_seq":3,"success":true,"command":"setBreakpoints","body":{"breakpoints":[{"verified":true,"line":3},{"verified":false,"line":9}]}}
+-> {"seq":4,"type":"request","command":"next","arguments":{"threadId":1}}
+<- {"seq":5,"type":"response","request_seq":4,"success":false,"command":"next","message":"The program is not paused"}
+-> {"seq":5,"type":"request","command":"configurationDone"}
+<- {"seq":6,"type":"response","request_seq":5,"success":true,"command":"configurationDone","body":null}
+<- {"seq":7,"type":"event","event":"stopped","body":{"reason":"entry","threadId":1,"allThreadsStopped":true}}
+-> {"seq":6,"type":"request","command":"threads"}
+<- {"seq":8,"type":"response","request_seq":6,"success":true,"command":"threads","body":{"threads":[{"id":1,"name":"main"}]}}
+-> {"seq":7,"type":"request","command":"continue","arguments":{"threadId":1}}
+<- {"seq":9,"type":"response","request_seq":7,"success":true,"command":"continue","body":{"allThreadsContinued":true}}
+<- {"seq":10,"type":"event","event":"stopped","body":{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}}
+-> {"seq":8,"type":"request","command":"stackTrace","arguments":{"threadId":1}}
+<- {"seq":11,"type":"response","request_seq":8,"success":true,"command":"stackTrace","body":{"stackFrames":[{"id":0,"name":"main","source":{"name":"$NAME","path":"$PROGRAM"},"line":3,"column":1}],"totalFrames":1}}
+-> {"seq":9,"type":"request","command":"scopes","arguments":{"frameId":0}}
+<- {"seq":12,"type":"response","request_seq":9,"success":true,"command":"scopes","body":{"scopes":[{"name":"Stack","variablesReference":1,"expensive":false},{"name":"Globals","variablesReference":2,"expensive":false}]}}
+-> {"seq":10,"type":"request","command":"variables","arguments":{"variablesReference":1}}
+<- {"seq":13,"type":"response","request_seq":10,"success":true,"command":"variables","body":{"variables":[{"name":"[0]","value":"ab","variablesReference":0}]}}
+-> {"seq":11,"type":"request","command":"variables","arguments":{"variablesReference":2}}
+<- {"seq":14,"type":"response","request_seq":11,"success":true,"command":"variables","body":{"variables":[]}}
+-> {"seq":12,"type":"request","command":"evaluate","arguments":{"expression":"\"x\" + \"y\"","frameId":0}}
+<- {"seq":15,"type":"response","request_seq":12,"success":true,"command":"evaluate","body":{"result":"xy","variablesReference":0}}
+-> {"seq":13,"type":"request","command":"evaluate","arguments":{"expression":"missing","frameId":0}}
+<- {"seq":16,"type":"response","request_seq":13,"success":false,"command":"evaluate","message":"Undefined variable 'missing'"}
+-> {"seq":14,"type":"request","command":"next","arguments":{"threadId":1}}
+<- {"seq":17,"type":"response","request_seq":14,"success":true,"command":"next","body":null}
+<- {"seq":18,"type":"event","event":"output","body":{"category":"stderr","output":"Cannot negate c\n"}}
+<- {"seq":19,"type":"event","event":"exited","body":{"exitCode":70}}
+<- {"seq":20,"type":"event","event":"terminated","body":{}}
+-> {"seq":15,"type":"request","command":"disconnect"}
+<- {"seq":21,"type":"response","request_seq":15,"success":true,"command":"disconnect","body":null}
+"#,
+        );
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        replay(
+            "nil\n",
+            r#"
+-> {"seq":1,"type":"request"
+<- {"seq":1,"type":"response","request_seq":null,"success":false,"command":null,"message":"Parse error: Unterminated object starting at 0"}
+-> {"seq":2,"type":"request","command":"disconnect"}
+<- {"seq":2,"type":"response","request_seq":2,"success":true,"command":"disconnect","body":null}
+"#,
+        );
+    }
+}
@@ -1,60 +1,236 @@
-use crate::{chunk::Chunk, opcode::OpCode, value::Value};
+use std::fmt::{self, Display, Write};
 
-pub fn disassemble_chunk(chunk: &Chunk) {
-    println!("== {} ==", chunk.name);
+use crate::{chunk::Chunk, json::Json, opcode::OpCode, value::Value};
 
-    let mut offset = 0;
-    while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset);
+/// A single decoded instruction along with everything needed to render it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub line: usize,
+    pub opcode: OpCode,
+    pub operands: Vec<u8>,
+    /// The constant the instruction loads, resolved from the constant pool.
+    pub constant: Option<Value>,
+    /// The offset control transfers to for jump instructions.
+    pub jump_target: Option<usize>,
+}
+
+impl Instruction {
+    /// Offset of the instruction that follows this one.
+    pub fn next_offset(&self) -> usize {
+        self.offset + 1 + self.operands.len()
+    }
+
+    fn fmt_body(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.constant, self.jump_target) {
+            (Some(value), _) => write!(f, "{}    {} {}", self.opcode.name(), self.operands[0], value),
+            (None, Some(target)) => write!(f, "{}    {} -> {}", self.opcode.name(), self.offset, target),
+            (None, None) if !self.operands.is_empty() => write!(f, "{}    {}", self.opcode.name(), self.operands[0]),
+            (None, None) => write!(f, "{}", self.opcode.name()),
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("offset", self.offset.into()),
+            ("line", self.line.into()),
+            ("opcode", self.opcode.name().into()),
+            ("operands", Json::Array(self.operands.iter().map(|&b| (b as usize).into()).collect())),
+            ("constant", self.constant.as_ref().map(value_to_json).into()),
+            ("jump_target", self.jump_target.into()),
+        ])
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04} {:4} ", self.offset, self.line)?;
+        self.fmt_body(f)
     }
 }
 
-macro_rules! simple_instruction {
-    ($name:tt, $offset:expr) => {
-        {
-            println!("{}", stringify!($name));
-            $offset + 1
+fn value_to_json(value: &Value) -> Json {
+    let (kind, value) = match value {
+        Value::String(s) => ("string", Json::String(s.clone())),
+        Value::Number(n) => ("number", Json::Number(*n)),
+        Value::Bool(b) => ("bool", Json::Bool(*b)),
+        Value::Nil => ("nil", Json::Null),
+        Value::List(list) => ("list", Json::Array(list.borrow().iter().map(value_to_json).collect())),
+        // keys aren't always strings, so entries are [key, value] pairs
+        Value::Map(map) => {
+            let entries =
+                map.borrow().iter().map(|(k, v)| Json::Array(vec![value_to_json(k), value_to_json(v)])).collect();
+            ("map", Json::Array(entries))
         }
+        Value::Native(native) => ("native", Json::String(native.name.clone())),
     };
+    Json::object(vec![("type", kind.into()), ("value", value)])
 }
 
-macro_rules! constant_instruction {
-    ($name:tt, $offset:expr, $chunk:expr) => {
-        {
-            let const_idx: u8 = $chunk.code[$offset + 1];
-            let value: Value = $chunk.get_const(const_idx as usize);
-            println!("{}    {} {}", stringify!($name), const_idx, value);
-            $offset + 2
+/// Decodes the instruction starting at `offset`,
+/// failing if the opcode is unknown or its operands run past the end of the chunk.
+pub fn decode_instruction(chunk: &Chunk, offset: usize) -> Result<Instruction, String> {
+    let opcode = OpCode::try_from(chunk.code[offset])
+        .map_err(|msg| format!("{} at offset {}", msg, offset))?;
+    let end = offset + 1 + opcode.operand_count();
+    if end > chunk.code.len() {
+        return Err(format!("Missing operand for {} at offset {}", opcode.name(), offset));
+    }
+    let operands: Vec<u8> = chunk.code[offset + 1..end].to_vec();
+
+    let constant = match opcode {
+        OpCode::Constant
+        | OpCode::AddConstant
+        | OpCode::LessConstant
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal => {
+            let index = operands[0] as usize;
+            match chunk.constants.get(index) {
+                Some(value) => Some(value.clone()),
+                None => return Err(format!("Constant index {} out of range at offset {}", index, offset)),
+            }
         }
+        _ => None,
     };
+
+    Ok(Instruction {
+        offset,
+        line: chunk.lines[offset],
+        opcode,
+        operands,
+        constant,
+        // no opcode transfers control yet
+        jump_target: None,
+    })
+}
+
+/// Decodes every instruction in the chunk.
+pub fn disassemble(chunk: &Chunk) -> Result<Vec<Instruction>, String> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let instruction = decode_instruction(chunk, offset)?;
+        offset = instruction.next_offset();
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+/// Renders the chunk as a human readable listing.
+/// Consecutive instructions on the same line show `|` in place of the line number.
+pub fn disassemble_to_string(chunk: &Chunk) -> Result<String, String> {
+    let mut out = format!("== {} ==\n", chunk.name);
+    let mut previous_line = None;
+    for instruction in disassemble(chunk)? {
+        if previous_line == Some(instruction.line) {
+            write!(out, "{:04}    | ", instruction.offset).unwrap();
+        } else {
+            write!(out, "{:04} {:4} ", instruction.offset, instruction.line).unwrap();
+        }
+        writeln!(out, "{}", Body(&instruction)).unwrap();
+        previous_line = Some(instruction.line);
+    }
+    Ok(out)
+}
+
+/// Renders the chunk as a JSON object.
+pub fn disassemble_to_json(chunk: &Chunk) -> Result<Json, String> {
+    Ok(Json::object(vec![
+        ("name", chunk.name.as_str().into()),
+        ("instructions", Json::Array(disassemble(chunk)?.iter().map(Instruction::to_json).collect())),
+    ]))
+}
+
+struct Body<'a>(&'a Instruction);
+
+impl Display for Body<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_body(f)
+    }
+}
+
+pub fn disassemble_chunk(chunk: &Chunk) {
+    match disassemble_to_string(chunk) {
+        Ok(listing) => print!("{}", listing),
+        Err(msg) => println!("== {} ==\n{}", chunk.name, msg),
+    }
 }
 
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
-
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
-    } else {
-        print!("{:4} ", chunk.lines[offset]);
-    }
-
-    let opcode = OpCode::from(chunk.code[offset]);
-
-    match opcode {
-        OpCode::Constant => constant_instruction!(CONSTANT, offset, chunk),
-        OpCode::Return => simple_instruction!(RETURN, offset),
-        OpCode::Negate => simple_instruction!(NEGATE, offset),
-        OpCode::Add => simple_instruction!(ADD, offset),
-        OpCode::Subtract => simple_instruction!(SUBTRACT, offset),
-        OpCode::Multiply => simple_instruction!(MULTIPLY, offset),
-        OpCode::Divide => simple_instruction!(DIVIDE, offset),
-        OpCode::Modulo => simple_instruction!(MODULO, offset),
-        OpCode::Nil => simple_instruction!(NIL, offset),
-        OpCode::True => simple_instruction!(TRUE, offset),
-        OpCode::False => simple_instruction!(FALSE, offset),
-        OpCode::Not => simple_instruction!(NOT, offset),
-        OpCode::Equal => simple_instruction!(EQUAL, offset),
-        OpCode::Greater => simple_instruction!(GREATER, offset),
-        OpCode::Less => simple_instruction!(LESS, offset),
+    match decode_instruction(chunk, offset) {
+        Ok(instruction) => {
+            println!("{}", instruction);
+            instruction.next_offset()
+        }
+        Err(msg) => {
+            println!("{:04} {}", offset, msg);
+            offset + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_disassemble_binary() {
+        let chunk = compile("\"a\" - 2").unwrap();
+        let instructions = disassemble(&chunk).unwrap();
+        let opcodes: Vec<OpCode> = instructions.iter().map(|i| i.opcode).collect();
+        assert_eq!(opcodes, vec![OpCode::Constant, OpCode::Constant, OpCode::Subtract, OpCode::Return]);
+        assert_eq!(instructions[1].offset, 2);
+        assert_eq!(instructions[1].operands, vec![1]);
+        assert_eq!(instructions[1].constant, Some(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_disassemble_to_string() {
+        let chunk = compile("-\"a\"").unwrap();
+        let expected = "== main ==\n\
+                        0000    1 CONSTANT    0 a\n\
+                        0002    | NEGATE\n\
+                        0003    | RETURN\n";
+        assert_eq!(disassemble_to_string(&chunk).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_collections() {
+        let chunk = compile("[x, 2][0] = {}").unwrap();
+        let expected = "== main ==\n\
+                        0000    1 GET_GLOBAL    0 x\n\
+                        0002    | CONSTANT    1 2\n\
+                        0004    | BUILD_LIST    2\n\
+                        0006    | CONSTANT    2 0\n\
+                        0008    | BUILD_MAP    0\n\
+                        0010    | INDEX_SET\n\
+                        0011    | RETURN\n";
+        assert_eq!(disassemble_to_string(&chunk).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_to_json() {
+        let chunk = compile("nil").unwrap();
+        let expected = "{\"name\":\"main\",\"instructions\":[\
+                        {\"offset\":0,\"line\":1,\"opcode\":\"NIL\",\"operands\":[],\"constant\":null,\"jump_target\":null},\
+                        {\"offset\":1,\"line\":1,\"opcode\":\"RETURN\",\"operands\":[],\"constant\":null,\"jump_target\":null}]}";
+        assert_eq!(disassemble_to_json(&chunk).unwrap().to_string(), expected);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut chunk = Chunk::default();
+        chunk.write(0xff, 1);
+        assert_eq!(decode_instruction(&chunk, 0).unwrap_err(), "Invalid opcode 255 at offset 0");
+
+        let mut chunk = Chunk::default();
+        chunk.write_opcode(OpCode::Constant, 1);
+        assert!(decode_instruction(&chunk, 0).is_err());
+        chunk.write(3, 1);
+        assert_eq!(
+            decode_instruction(&chunk, 0).unwrap_err(),
+            "Constant index 3 out of range at offset 0"
+        );
     }
 }
@@ -0,0 +1,255 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::vm::{DebugHook, VM};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    Continue,
+    /// Pause at the next line in the same or an outer frame.
+    Over,
+    /// Pause at the next line anywhere.
+    Into,
+    /// Pause at the next line in an outer frame.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step,
+}
+
+/// Breakpoints and the pending step request, shared by the debugger frontends.
+#[derive(Debug, Default)]
+pub struct Stepper {
+    pub breakpoints: BTreeSet<usize>,
+    entry: bool,
+    // the step requested and the call depth it was requested at
+    step: Option<(Step, usize)>,
+}
+
+impl Stepper {
+    pub fn new(stop_on_entry: bool) -> Self {
+        Self { entry: stop_on_entry, ..Self::default() }
+    }
+
+    pub fn resume(&mut self, step: Step, depth: usize) {
+        self.step = Some((step, depth));
+    }
+
+    /// Decides whether to pause on reaching `line` at call depth `depth`.
+    pub fn should_pause(&mut self, line: usize, depth: usize) -> Option<StopReason> {
+        let reason = if std::mem::take(&mut self.entry) {
+            StopReason::Entry
+        } else if self.breakpoints.contains(&line) {
+            StopReason::Breakpoint
+        } else {
+            match self.step {
+                Some((Step::Into, _)) => StopReason::Step,
+                Some((Step::Over, from)) if depth <= from => StopReason::Step,
+                Some((Step::Out, from)) if depth < from => StopReason::Step,
+                _ => return None,
+            }
+        };
+        self.step = None;
+        Some(reason)
+    }
+}
+
+const HELP: &str = "\
+break <line>     set a breakpoint (b)
+delete <line>    remove a breakpoint (d)
+continue         run to the next breakpoint (c)
+next             step over (n)
+step             step into (s)
+finish           step out (f)
+backtrace        show the call stack (bt)
+locals           show the values on the current frame's stack (l)
+globals          show global variables (g)
+print <expr>     evaluate an expression in the paused program (p)
+list             show the source around the current line
+quit             stop the program (q)
+";
+
+/// A command-line debugger that stops on entry and then reads commands from `input`.
+pub struct Debugger<R, W> {
+    stepper: Stepper,
+    input: R,
+    output: W,
+    source: Vec<String>,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    pub fn new(input: R, output: W, source: &str) -> Self {
+        Self { stepper: Stepper::new(true), input, output, source: source.lines().map(str::to_string).collect() }
+    }
+
+    fn source_line(&self, line: usize) -> &str {
+        line.checked_sub(1).and_then(|index| self.source.get(index)).map_or("", |text| text.trim())
+    }
+
+    /// Handles one command, returning the step to resume with if it ends the pause.
+    fn command(&mut self, vm: &mut VM, command: &str) -> Option<Option<Step>> {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+        let out = &mut self.output;
+        match name {
+            "" => {}
+            "b" | "break" | "d" | "delete" => match argument.parse::<usize>() {
+                Ok(line) if name.starts_with('b') => {
+                    self.stepper.breakpoints.insert(line);
+                    let _ = writeln!(out, "Breakpoint set at line {}", line);
+                }
+                Ok(line) if self.stepper.breakpoints.remove(&line) => {
+                    let _ = writeln!(out, "Breakpoint removed at line {}", line);
+                }
+                Ok(line) => {
+                    let _ = writeln!(out, "No breakpoint at line {}", line);
+                }
+                Err(_) => {
+                    let _ = writeln!(out, "Expected a line number");
+                }
+            },
+            "c" | "continue" => return Some(Some(Step::Continue)),
+            "n" | "next" => return Some(Some(Step::Over)),
+            "s" | "step" => return Some(Some(Step::Into)),
+            "f" | "finish" => return Some(Some(Step::Out)),
+            "q" | "quit" => return Some(None),
+            "bt" | "backtrace" => {
+                for (index, (function, line)) in vm.stack_trace().iter().enumerate() {
+                    let _ = writeln!(out, "#{} {} at line {}", index, function, line);
+                }
+            }
+            "l" | "locals" => {
+                let values = vm.stack_values();
+                if values.is_empty() {
+                    let _ = writeln!(out, "(empty)");
+                }
+                for (slot, value) in values.iter().enumerate() {
+                    let _ = writeln!(out, "[{}] {}", slot, value);
+                }
+            }
+            "g" | "globals" => {
+                for (name, value) in vm.globals() {
+                    let _ = writeln!(out, "{} = {}", name, value);
+                }
+            }
+            "p" | "print" => {
+                let _ = match vm.evaluate(argument) {
+                    Ok(value) => writeln!(out, "{}", value),
+                    Err(msg) => writeln!(out, "error: {}", msg),
+                };
+            }
+            "list" => {
+                let current = vm.current_line();
+                for line in current.saturating_sub(2).max(1)..=current + 2 {
+                    if line <= self.source.len() {
+                        let marker = if line == current { "->" } else { "  " };
+                        let _ = writeln!(self.output, "{} {:4} {}", marker, line, self.source[line - 1]);
+                    }
+                }
+            }
+            "h" | "help" => {
+                let _ = write!(out, "{}", HELP);
+            }
+            _ => {
+                let _ = writeln!(out, "Unknown command '{}'. Type 'help' for a list.", name);
+            }
+        }
+        None
+    }
+}
+
+impl<R: BufRead, W: Write> DebugHook for Debugger<R, W> {
+    fn on_line(&mut self, vm: &mut VM, line: usize) -> bool {
+        let depth = vm.call_depth();
+        let Some(reason) = self.stepper.should_pause(line, depth) else {
+            return true;
+        };
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+        };
+        let text = self.source_line(line).to_string();
+        let _ = writeln!(self.output, "Stopped at line {} ({}): {}", line, reason, text);
+        loop {
+            let _ = write!(self.output, "(lox) ");
+            let _ = self.output.flush();
+            let mut command = String::new();
+            // quit when the input ends
+            if !matches!(self.input.read_line(&mut command), Ok(n) if n > 0) {
+                return false;
+            }
+            match self.command(vm, command.trim()) {
+                Some(Some(step)) => {
+                    self.stepper.resume(step, depth);
+                    return true;
+                }
+                Some(None) => return false,
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{InterpretResult, OutputBuffer};
+
+    fn debug(source: &str, commands: &str) -> (InterpretResult, String) {
+        let output = OutputBuffer::new();
+        let mut vm = VM::default();
+        vm.set_stdout(output.clone());
+        vm.set_stderr(output.clone());
+        vm.set_global("x", 20.0);
+        let input = std::io::Cursor::new(commands.as_bytes().to_vec());
+        vm.set_debug_hook(Debugger::new(input, output.clone(), source));
+        let result = vm.interpret(source);
+        (result, output.contents())
+    }
+
+    #[test]
+    fn test_breakpoints_and_inspection() {
+        let source = "x = x\n  + 1\n  + 2\n  * x";
+        let (result, output) = debug(source, "b 3\nc\nl\nbt\np x * 2\np y\ng\nn\nc\n");
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            output,
+            "Stopped at line 1 (entry): x = x\n\
+             (lox) Breakpoint set at line 3\n\
+             (lox) Stopped at line 3 (breakpoint): + 2\n\
+             (lox) [0] 21\n\
+             (lox) #0 main at line 3\n\
+             (lox) 40\n\
+             (lox) error: Undefined variable 'y'\n\
+             (lox) x = 20\n\
+             (lox) Stopped at line 4 (step): * x\n\
+             (lox) 61\n"
+        );
+    }
+
+    #[test]
+    fn test_quit() {
+        let (result, output) = debug("1\n+ x", "q\n");
+        assert!(matches!(result, InterpretResult::Aborted(_)));
+        assert!(output.starts_with("Stopped at line 1 (entry): 1\n(lox) "));
+    }
+
+    #[test]
+    fn test_stepper() {
+        let mut stepper = Stepper::new(false);
+        stepper.breakpoints.insert(5);
+        assert_eq!(stepper.should_pause(1, 1), None);
+        assert_eq!(stepper.should_pause(5, 1), Some(StopReason::Breakpoint));
+        stepper.resume(Step::Over, 2);
+        assert_eq!(stepper.should_pause(6, 3), None);
+        assert_eq!(stepper.should_pause(7, 2), Some(StopReason::Step));
+        stepper.resume(Step::Out, 2);
+        assert_eq!(stepper.should_pause(8, 2), None);
+        assert_eq!(stepper.should_pause(9, 1), Some(StopReason::Step));
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+
+/// A range of source text as a byte offset and length. An empty span marks
+/// code without a known position, such as instructions in hand-built chunks.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Span {
+    pub fn new(offset: usize, length: usize) -> Self {
+        Self { offset, length }
+    }
+
+    /// The span from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.offset, (other.offset + other.length).saturating_sub(self.offset))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A message about a piece of a program, along with where it is and what might fix it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// The source to underline. When empty the whole of `line` is underlined.
+    pub span: Span,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, line: usize, span: Span) -> Self {
+        Self { severity: Severity::Error, message: message.to_string(), line, span, notes: Vec::new(), help: None }
+    }
+}
+
+/// The 1-based line and column (counted in characters) of a byte offset.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// Renders diagnostics the way compilers print them: a header, the location,
+/// the offending source line with the span underlined, then notes and help.
+///
+/// ```text
+/// error: Expect ')' after expression
+///  --> script.lox:1:7
+///   |
+/// 1 | (1 + 2
+///   |       ^
+///   = note: unclosed '(' opened at 1:1
+///   = help: did you forget a ')'?
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Renderer {
+    pub path: String,
+    /// Whether to highlight with ANSI escape codes.
+    pub color: bool,
+}
+
+impl Renderer {
+    pub fn new(path: &str, color: bool) -> Self {
+        Self { path: path.to_string(), color }
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, source: &str) -> String {
+        let (label, style) = match diagnostic.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+
+        // find the line, the byte range to underline within it, and the column that starts at
+        let (line, start, end) = if diagnostic.span.is_empty() {
+            let (start, end) = line_bounds(source, diagnostic.line);
+            let text = &source[start..end];
+            let indent = text.len() - text.trim_start().len();
+            (diagnostic.line, start + indent, start + text.trim_end().len())
+        } else {
+            let span = diagnostic.span;
+            let (line, _) = line_column(source, span.offset);
+            let (_, line_end) = line_bounds(source, line);
+            let start = span.offset.min(source.len());
+            (line, start, (span.offset + span.length).min(line_end))
+        };
+        let (line_start, line_end) = line_bounds(source, line);
+        let text = &source[line_start..line_end];
+        let column = source[line_start..start].chars().count() + 1;
+
+        let width = line.to_string().len();
+        let gutter = self.paint(BLUE, &format!("{} |", " ".repeat(width)));
+        let mut out = String::new();
+        let _ = writeln!(out, "{}{}", self.paint(style, label), self.paint(BOLD, &format!(": {}", diagnostic.message)));
+        let _ = writeln!(out, "{}{} {}:{}:{}", " ".repeat(width), self.paint(BLUE, "-->"), self.path, line, column);
+        let _ = writeln!(out, "{}", gutter);
+        let _ = writeln!(out, "{} {}", self.paint(BLUE, &format!("{} |", line)), text);
+
+        // keep tabs so the carets line up with the text above them
+        let padding: String =
+            source[line_start..start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        let carets = source[start..end.max(start)].chars().count().max(1);
+        let _ = writeln!(out, "{} {}{}", gutter, padding, self.paint(style, &"^".repeat(carets)));
+
+        let indent = " ".repeat(width + 1);
+        for note in &diagnostic.notes {
+            let _ = writeln!(out, "{}{} {}: {}", indent, self.paint(BLUE, "="), self.paint(BOLD, "note"), note);
+        }
+        if let Some(help) = &diagnostic.help {
+            let _ = writeln!(out, "{}{} {}: {}", indent, self.paint(BLUE, "="), self.paint(CYAN, "help"), help);
+        }
+        out
+    }
+}
+
+/// The byte offsets of the start and end (excluding the newline) of a 1-based line.
+fn line_bounds(source: &str, line: usize) -> (usize, usize) {
+    let start: usize = source.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+    let start = start.min(source.len());
+    let end = source[start..].find('\n').map_or(source.len(), |n| start + n);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "x = 1\n\t+ -\"a\"\n";
+        let mut diagnostic = Diagnostic::error("Operand must be a number", 2, Span::new(9, 1));
+        diagnostic.notes.push("the operand is a string".to_string());
+        diagnostic.help = Some("convert it first".to_string());
+        let expected = "\
+error: Operand must be a number
+ --> test.lox:2:4
+  |
+2 | \t+ -\"a\"
+  | \t  ^
+  = note: the operand is a string
+  = help: convert it first
+";
+        assert_eq!(Renderer::new("test.lox", false).render(&diagnostic, source), expected);
+
+        let colored = Renderer::new("test.lox", true).render(&diagnostic, source);
+        assert!(colored.starts_with("\x1b[1;31merror\x1b[0m\x1b[1m: Operand must be a number\x1b[0m\n"));
+    }
+
+    #[test]
+    fn test_spans_past_the_line() {
+        // an empty span underlines the whole line without its indentation
+        let source = "a\n  b + c  \n";
+        let diagnostic = Diagnostic::error("Bad", 2, Span::default());
+        let rendered = Renderer::new("t", false).render(&diagnostic, source);
+        assert!(rendered.ends_with("2 |   b + c  \n  |   ^^^^^\n"), "{}", rendered);
+
+        // a span at the end of the source points just past the last character
+        let diagnostic = Diagnostic::error("Bad", 1, Span::new(3, 1));
+        let rendered = Renderer::new("t", false).render(&diagnostic, "1 +");
+        assert!(rendered.contains(" --> t:1:4\n"));
+        assert!(rendered.ends_with("1 | 1 +\n  |    ^\n"), "{}", rendered);
+        assert_eq!(line_column("ab\ncé d", 7), (2, 4));
+    }
+}
@@ -0,0 +1,440 @@
+//! C ABI for embedding the VM in non-Rust hosts.
+//!
+//! Every pointer passed in must either be null (where documented) or have come
+//! from the matching `lox_*` constructor and not yet been freed. Strings are
+//! NUL-terminated UTF-8. Strings returned by the library are owned by the object
+//! they were fetched from and stay valid until it is next mutated or freed.
+//!
+//! `include/lox.h` is generated from the declarations in this file by
+//! `rlox header`; a test keeps the two in sync.
+#![allow(clippy::missing_safety_doc)]
+
+use std::any::Any;
+use std::cell::OnceCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::value::Value;
+use crate::vm::{InterpretResult, OutputBuffer, VM};
+
+pub const LOX_OK: c_int = 0;
+pub const LOX_COMPILE_ERROR: c_int = 1;
+pub const LOX_RUNTIME_ERROR: c_int = 2;
+pub const LOX_ABORTED: c_int = 3;
+
+pub const LOX_NIL: c_int = 0;
+pub const LOX_BOOL: c_int = 1;
+pub const LOX_NUMBER: c_int = 2;
+pub const LOX_STRING: c_int = 3;
+pub const LOX_NATIVE: c_int = 4;
+pub const LOX_LIST: c_int = 5;
+pub const LOX_MAP: c_int = 6;
+
+/// A native function implemented by the host. It receives the `userdata` given at
+/// registration and returns a new value, or null to raise a runtime error.
+pub type LoxNativeFn =
+    Option<unsafe extern "C" fn(userdata: *mut c_void, args: *const *const LoxValue, argc: c_int) -> *mut LoxValue>;
+
+/// A VM whose error output is captured so hosts can fetch it with `lox_last_error`.
+pub struct LoxVM {
+    vm: VM,
+    stderr: OutputBuffer,
+    last_error: Option<CString>,
+}
+
+pub struct LoxValue {
+    value: Value,
+    // kept alive so `lox_value_as_string` can hand out a pointer into it; values
+    // never change once created, so it is only formatted the first time
+    string: OnceCell<CString>,
+}
+
+impl LoxValue {
+    fn new(value: Value) -> *mut LoxValue {
+        Box::into_raw(Box::new(LoxValue { value, string: OnceCell::new() }))
+    }
+}
+
+/// Converts `s` for C, cutting it at the first interior NUL byte.
+fn to_c_string(s: &str) -> CString {
+    let end = s.find('\0').unwrap_or(s.len());
+    CString::new(&s[..end]).unwrap()
+}
+
+// Every entry point runs its body through `guard`: a panic unwinding into the C
+// caller would abort the host, so it is turned into a failure result instead.
+fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(on_panic)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("unknown panic", String::as_str),
+    }
+}
+
+unsafe fn from_c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+#[no_mangle]
+pub extern "C" fn lox_vm_new() -> *mut LoxVM {
+    guard(ptr::null_mut(), || {
+        let stderr = OutputBuffer::new();
+        let mut vm = VM::default();
+        vm.set_stderr(stderr.clone());
+        Box::into_raw(Box::new(LoxVM { vm, stderr, last_error: None }))
+    })
+}
+
+/// Frees a VM. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn lox_vm_free(vm: *mut LoxVM) {
+    if !vm.is_null() {
+        guard((), || drop(Box::from_raw(vm)));
+    }
+}
+
+/// Compiles and runs `source`, returning one of the `LOX_OK`… result codes.
+/// A failure inside the VM itself is reported as a runtime error.
+#[no_mangle]
+pub unsafe extern "C" fn lox_interpret(vm: *mut LoxVM, source: *const c_char) -> c_int {
+    let vm = &mut *vm;
+    vm.stderr.clear();
+    vm.last_error = None;
+    let Some(source) = from_c_str(source) else {
+        vm.last_error = Some(to_c_string("Source is null or not valid UTF-8"));
+        return LOX_COMPILE_ERROR;
+    };
+    let result = match panic::catch_unwind(AssertUnwindSafe(|| vm.vm.interpret(source))) {
+        Ok(result) => result,
+        Err(payload) => {
+            vm.last_error = Some(to_c_string(&format!("Internal error: {}", panic_message(&*payload))));
+            return LOX_RUNTIME_ERROR;
+        }
+    };
+    let errors = vm.stderr.contents();
+    if !errors.is_empty() {
+        vm.last_error = Some(to_c_string(errors.trim_end()));
+    }
+    match result {
+        InterpretResult::Ok => LOX_OK,
+        InterpretResult::CompileError => LOX_COMPILE_ERROR,
+        InterpretResult::RuntimeError => LOX_RUNTIME_ERROR,
+        InterpretResult::Aborted(_) => LOX_ABORTED,
+    }
+}
+
+/// The error messages from the last `lox_interpret` call, or null if it succeeded.
+#[no_mangle]
+pub unsafe extern "C" fn lox_last_error(vm: *const LoxVM) -> *const c_char {
+    guard(ptr::null(), || match &(*vm).last_error {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Defines a global function taking `arity` arguments that calls `function`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_define_native(
+    vm: *mut LoxVM,
+    name: *const c_char,
+    arity: c_int,
+    function: LoxNativeFn,
+    userdata: *mut c_void,
+) -> bool {
+    let (Some(name), Some(function)) = (from_c_str(name), function) else {
+        return false;
+    };
+    let Ok(arity) = usize::try_from(arity) else {
+        return false;
+    };
+    let native_name = name.to_string();
+    guard(false, || {
+        (*vm).vm.define_native(name, arity, move |args| {
+            let args: Vec<LoxValue> =
+                args.iter().map(|value| LoxValue { value: value.clone(), string: OnceCell::new() }).collect();
+            let pointers: Vec<*const LoxValue> = args.iter().map(|arg| arg as *const LoxValue).collect();
+            let result = function(userdata, pointers.as_ptr(), pointers.len() as c_int);
+            if result.is_null() {
+                return Err(format!("Native function '{}' failed", native_name));
+            }
+            Ok(Box::from_raw(result).value)
+        });
+        true
+    })
+}
+
+/// Returns a new value holding the global `name`, or null if it is undefined.
+#[no_mangle]
+pub unsafe extern "C" fn lox_get_global(vm: *const LoxVM, name: *const c_char) -> *mut LoxValue {
+    guard(ptr::null_mut(), || match from_c_str(name).and_then(|name| (*vm).vm.get_global(name)) {
+        Some(value) => LoxValue::new(value),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Sets the global `name` to a copy of `value`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_set_global(vm: *mut LoxVM, name: *const c_char, value: *const LoxValue) -> bool {
+    guard(false, || match from_c_str(name) {
+        Some(name) => {
+            (*vm).vm.set_global(name, (*value).value.clone());
+            true
+        }
+        None => false,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_nil() -> *mut LoxValue {
+    guard(ptr::null_mut(), || LoxValue::new(Value::Nil))
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_bool(value: bool) -> *mut LoxValue {
+    guard(ptr::null_mut(), || LoxValue::new(Value::Bool(value)))
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_number(value: f64) -> *mut LoxValue {
+    guard(ptr::null_mut(), || LoxValue::new(Value::Number(value)))
+}
+
+/// Copies `value` into a new string value, or returns null if it is not valid UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_string(value: *const c_char) -> *mut LoxValue {
+    guard(ptr::null_mut(), || match from_c_str(value) {
+        Some(value) => LoxValue::new(Value::from(value)),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Frees a value. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_free(value: *mut LoxValue) {
+    if !value.is_null() {
+        guard((), || drop(Box::from_raw(value)));
+    }
+}
+
+/// One of `LOX_NIL`, `LOX_BOOL`, `LOX_NUMBER`, `LOX_STRING`, `LOX_NATIVE`, `LOX_LIST` or `LOX_MAP`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_type(value: *const LoxValue) -> c_int {
+    guard(LOX_NIL, || match (*value).value {
+        Value::Nil => LOX_NIL,
+        Value::Bool(_) => LOX_BOOL,
+        Value::Number(_) => LOX_NUMBER,
+        Value::String(_) => LOX_STRING,
+        Value::Native(_) => LOX_NATIVE,
+        Value::List(_) => LOX_LIST,
+        Value::Map(_) => LOX_MAP,
+    })
+}
+
+/// Whether the value is truthy, so it works for every type.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_bool(value: *const LoxValue) -> bool {
+    guard(false, || (*value).value.is_truthy())
+}
+
+/// The number held by the value, or NaN for other types.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_number(value: *const LoxValue) -> f64 {
+    guard(f64::NAN, || match (*value).value {
+        Value::Number(n) => n,
+        _ => f64::NAN,
+    })
+}
+
+/// The value printed as a string, owned by `value` and valid until it is freed,
+/// or null if it could not be formatted.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_string(value: *const LoxValue) -> *const c_char {
+    let value = &*value;
+    guard(ptr::null(), || value.string.get_or_init(|| to_c_string(&value.value.to_string())).as_ptr())
+}
+
+const PRELUDE: &str = "\
+#ifndef LOX_H
+#define LOX_H
+
+/* Generated by `rlox header` from src/ffi.rs. Do not edit. */
+
+#include <stdbool.h>
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+typedef struct LoxVM LoxVM;
+typedef struct LoxValue LoxValue;
+";
+
+const POSTLUDE: &str = "
+#ifdef __cplusplus
+}
+#endif
+
+#endif
+";
+
+/// Renders the C header for this module from its own source, so the two cannot drift apart.
+pub fn header() -> String {
+    let source = include_str!("ffi.rs");
+    let mut out = String::from(PRELUDE);
+    let mut docs: Vec<&str> = Vec::new();
+    let mut lines = source.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if let Some(doc) = line.strip_prefix("/// ") {
+            docs.push(doc);
+            continue;
+        }
+        if line.starts_with("#[") {
+            continue;
+        }
+        if let Some(constant) = line.strip_prefix("pub const LOX_") {
+            let (name, value) = constant.split_once(": c_int = ").unwrap();
+            if name == "OK" || name == "NIL" {
+                out.push('\n');
+            }
+            out += &format!("#define LOX_{} {}\n", name, value.trim_end_matches(';'));
+        } else if line.starts_with("pub type LoxNativeFn") {
+            let mut declaration = line.to_string();
+            while !declaration.ends_with(';') {
+                declaration += lines.next().unwrap().trim();
+            }
+            let signature = declaration.split_once("fn(").unwrap().1;
+            let (params, ret) = signature.split_once(") -> ").unwrap();
+            out.push('\n');
+            write_docs(&mut out, &docs);
+            out += &format!(
+                "typedef {}(*LoxNativeFn)({});\n",
+                c_type(ret.trim_end_matches(">;")),
+                c_params(params)
+            );
+        } else if line.starts_with("pub extern \"C\" fn") || line.starts_with("pub unsafe extern \"C\" fn") {
+            let mut declaration = line.to_string();
+            while !declaration.ends_with('{') {
+                declaration += lines.next().unwrap().trim();
+            }
+            let signature = declaration.split_once("fn ").unwrap().1.trim_end_matches(" {");
+            let (name, rest) = signature.split_once('(').unwrap();
+            let (params, ret) = rest.rsplit_once(')').unwrap();
+            let ret = ret.strip_prefix(" -> ").unwrap_or("()");
+            out.push('\n');
+            write_docs(&mut out, &docs);
+            out += &format!("{}{}({});\n", c_type(ret), name, c_params(params));
+        }
+        docs.clear();
+    }
+    out + POSTLUDE
+}
+
+fn write_docs(out: &mut String, docs: &[&str]) {
+    if !docs.is_empty() {
+        *out += &format!("/* {} */\n", docs.join("\n   ").replace('`', ""));
+    }
+}
+
+fn c_params(params: &str) -> String {
+    let params: Vec<String> = params
+        .split(',')
+        .map(str::trim)
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, ty) = param.split_once(": ").unwrap();
+            c_type(ty) + name
+        })
+        .collect();
+    if params.is_empty() {
+        "void".to_string()
+    } else {
+        params.join(", ")
+    }
+}
+
+/// The C spelling of a Rust type, with a trailing space or `*` ready for the name.
+fn c_type(ty: &str) -> String {
+    if let Some(pointee) = ty.strip_prefix("*const ") {
+        let pointee = c_type(pointee);
+        return if pointee.ends_with('*') { format!("{}const *", pointee) } else { format!("const {}*", pointee) };
+    }
+    if let Some(pointee) = ty.strip_prefix("*mut ") {
+        return format!("{}*", c_type(pointee));
+    }
+    let name = match ty {
+        "()" => "void",
+        "c_int" => "int",
+        "c_char" => "char",
+        "c_void" => "void",
+        "f64" => "double",
+        "bool" => "bool",
+        other => other,
+    };
+    format!("{} ", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn add(_: *mut c_void, args: *const *const LoxValue, argc: c_int) -> *mut LoxValue {
+        let args = std::slice::from_raw_parts(args, argc as usize);
+        lox_value_number(lox_value_as_number(args[0]) + lox_value_as_number(args[1]))
+    }
+
+    #[test]
+    fn test_round_trip_through_c_abi() {
+        unsafe {
+            let vm = lox_vm_new();
+            (*vm).vm.set_stdout(std::io::sink());
+            assert!(lox_define_native(vm, c"add".as_ptr(), 2, Some(add), ptr::null_mut()));
+            // assigning to an undefined global is an error, so declare it first
+            let nil = lox_value_nil();
+            assert!(lox_set_global(vm, c"total".as_ptr(), nil));
+            lox_value_free(nil);
+            assert_eq!(lox_interpret(vm, c"total = add(1, 2)".as_ptr()), LOX_OK);
+            assert!(lox_last_error(vm).is_null());
+
+            let total = lox_get_global(vm, c"total".as_ptr());
+            assert_eq!(lox_value_type(total), LOX_NUMBER);
+            assert_eq!(lox_value_as_number(total), 3.0);
+            assert_eq!(CStr::from_ptr(lox_value_as_string(total)), c"3");
+            lox_value_free(total);
+
+            assert_eq!(lox_interpret(vm, c"add(1)".as_ptr()), LOX_RUNTIME_ERROR);
+            assert_eq!(CStr::from_ptr(lox_last_error(vm)), c"Expected 2 arguments but got 1");
+            assert_eq!(lox_interpret(vm, c"(".as_ptr()), LOX_COMPILE_ERROR);
+            lox_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn test_panics_do_not_unwind_into_the_host() {
+        unsafe {
+            let vm = lox_vm_new();
+            (*vm).vm.set_stdout(std::io::sink());
+            (*vm).vm.define_native("boom", 0, |_| panic!("boom"));
+            assert_eq!(lox_interpret(vm, c"boom()".as_ptr()), LOX_RUNTIME_ERROR);
+            assert_eq!(CStr::from_ptr(lox_last_error(vm)), c"Internal error: boom");
+            // the VM is still usable afterwards
+            assert_eq!(lox_interpret(vm, c"1 + 2".as_ptr()), LOX_OK);
+            assert!(lox_last_error(vm).is_null());
+            lox_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn test_header_is_up_to_date() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/include/lox.h");
+        let checked_in = std::fs::read_to_string(path).unwrap_or_default();
+        assert!(checked_in == header(), "include/lox.h is stale, regenerate it with `rlox header > include/lox.h`");
+    }
+}
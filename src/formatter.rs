@@ -0,0 +1,225 @@
+use crate::scanner::{Scanner, Token, TokenType};
+
+const INDENT: &str = "    ";
+
+/// Formats Lox source in the canonical style.
+///
+/// Only whitespace changes: line breaks are kept where the author put them
+/// (with runs of blank lines collapsed to one), spacing around tokens is
+/// normalized, blocks are indented by four spaces, and lines that continue an
+/// unfinished statement get one extra level. Map literals are laid out like
+/// lists rather than blocks. Comments are preserved. The result is checked to
+/// scan to the same tokens as the input.
+pub fn format(source: &str) -> Result<String, String> {
+    let tokens = scan(source)?;
+    let maps = map_braces(&tokens);
+    let block = |index: usize, token_type: TokenType| tokens[index].token_type == token_type && !maps[index];
+    let map = |index: usize, token_type: TokenType| tokens[index].token_type == token_type && maps[index];
+    let mut out = String::new();
+    let mut depth: usize = 0;
+    let mut parens: usize = 0;
+    let mut statement_start = true;
+    let mut previous: Option<usize> = None;
+    // the last token that was not a comment, for telling unary minus from binary
+    let mut previous_code: Option<usize> = None;
+    let mut unary = false;
+
+    for (index, token) in tokens.iter().enumerate() {
+        let closing = matches!(token.token_type, TokenType::RightParen | TokenType::RightBracket)
+            || map(index, TokenType::RightBrace)
+            || continues_string(token);
+        if block(index, TokenType::RightBrace) {
+            depth = depth.saturating_sub(1);
+        }
+        if closing {
+            parens = parens.saturating_sub(1);
+        }
+
+        if let Some(prev_index) = previous {
+            let prev = &tokens[prev_index];
+            let gap = &source[prev.offset + prev.lexeme.len()..token.offset];
+            let newlines = gap.matches('\n').count();
+            let forced = matches!(prev.token_type, TokenType::Comment | TokenType::Semicolon)
+                || block(prev_index, TokenType::LeftBrace)
+                || block(index, TokenType::RightBrace)
+                || (block(prev_index, TokenType::RightBrace) && token.token_type != TokenType::Semicolon);
+            // `{"a": 1}` hugs its braces like a list does its brackets, and is
+            // indexed like one: `{"a": 1}["a"]`
+            let hugged = map(prev_index, TokenType::LeftBrace)
+                || map(index, TokenType::RightBrace)
+                || (map(prev_index, TokenType::RightBrace) && token.token_type == TokenType::LeftBracket);
+            if newlines > 0 || forced {
+                out.push('\n');
+                if newlines > 1 {
+                    out.push('\n');
+                }
+                let continuation = !statement_start && parens == 0 && !closing;
+                let level = depth + parens + usize::from(continuation);
+                out.push_str(&INDENT.repeat(level));
+            } else if !unary && !hugged && needs_space(prev, token) {
+                out.push(' ');
+            }
+        }
+
+        match token.token_type {
+            TokenType::Comment => out.push_str(token.lexeme.trim_end()),
+            _ => out.push_str(token.lexeme),
+        }
+
+        match token.token_type {
+            TokenType::LeftBrace if maps[index] => parens += 1,
+            TokenType::LeftBrace => depth += 1,
+            TokenType::LeftParen | TokenType::LeftBracket | TokenType::Interpolation => parens += 1,
+            _ => {}
+        }
+        if token.token_type != TokenType::Comment {
+            unary = match token.token_type {
+                TokenType::Bang => true,
+                TokenType::Minus => {
+                    !previous_code.is_some_and(|i| ends_operand(&tokens[i]) || map(i, TokenType::RightBrace))
+                }
+                _ => false,
+            };
+            statement_start = token.token_type == TokenType::Semicolon
+                || block(index, TokenType::LeftBrace)
+                || block(index, TokenType::RightBrace);
+            previous_code = Some(index);
+        }
+        previous = Some(index);
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+
+    let expected: Vec<(TokenType, &str)> = code_tokens(&tokens);
+    let formatted = scan(&out)?;
+    if code_tokens(&formatted) != expected {
+        return Err("Formatting would change the meaning of the program".to_string());
+    }
+    Ok(out)
+}
+
+fn scan(source: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut scanner = Scanner::with_comments(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Eof => return Ok(tokens),
+            TokenType::Error => return Err(format!("[line {}] Error: {}", token.line, token.lexeme)),
+            _ => tokens.push(token),
+        }
+    }
+}
+
+/// Marks the braces of map literals, which open where an operand is expected,
+/// including at the start of the source, as opposed to those of blocks.
+fn map_braces(tokens: &[Token]) -> Vec<bool> {
+    let mut maps = vec![false; tokens.len()];
+    let mut open: Vec<usize> = Vec::new();
+    let mut previous: Option<TokenType> = None;
+    for (index, token) in tokens.iter().enumerate() {
+        match token.token_type {
+            TokenType::Comment => continue,
+            TokenType::LeftBrace => {
+                maps[index] = previous.is_none_or(TokenType::expects_operand);
+                open.push(index);
+            }
+            TokenType::RightBrace => {
+                if let Some(start) = open.pop() {
+                    maps[index] = maps[start];
+                }
+            }
+            _ => {}
+        }
+        previous = Some(token.token_type);
+    }
+    maps
+}
+
+fn code_tokens<'src>(tokens: &[Token<'src>]) -> Vec<(TokenType, &'src str)> {
+    tokens
+        .iter()
+        .filter(|token| token.token_type != TokenType::Comment)
+        .map(|token| (token.token_type, token.lexeme))
+        .collect()
+}
+
+/// Whether a token continues a string after the `}` closing an interpolation.
+fn continues_string(token: &Token) -> bool {
+    matches!(token.token_type, TokenType::String | TokenType::Interpolation) && token.lexeme.starts_with('}')
+}
+
+/// Whether a token can be the left operand of a binary operator.
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token.token_type,
+        TokenType::Identifier
+            | TokenType::Number
+            | TokenType::String
+            | TokenType::RightParen
+            | TokenType::RightBracket
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::This
+            | TokenType::Super
+    )
+}
+
+/// Whether two tokens on the same line are separated by a space. Unary
+/// operators are handled by the caller.
+fn needs_space(prev: &Token, token: &Token) -> bool {
+    use TokenType::*;
+    // interpolations hug their braces: `"${x}"`
+    if prev.token_type == Interpolation || continues_string(token) {
+        return false;
+    }
+    match (prev.token_type, token.token_type) {
+        (_, Comment) => true,
+        (_, RightParen | RightBracket | Comma | Colon | Semicolon | Dot) => false,
+        (LeftParen | LeftBracket | Dot, _) => false,
+        // calls and indexing: `f(x)`, `f(x)(y)` and `a[i][j]`
+        (Identifier | RightParen | RightBracket, LeftParen | LeftBracket) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source = "// totals\n\n\n\ntotal=add( - x,!y ,  \"a  b\" )   // sum\n*(1+2)\n  // done\n";
+        let expected = "// totals\n\ntotal = add(-x, !y, \"a  b\") // sum\n    * (1 + 2)\n    // done\n";
+        assert_eq!(format(source).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_blocks_and_calls() {
+        let source = "if (x) {x=1;{ f (a)(b) - -1;}}";
+        let expected = "if (x) {\n    x = 1;\n    {\n        f(a)(b) - -1;\n    }\n}\n";
+        assert_eq!(format(source).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+        assert_eq!(format("add(\n1,\n2\n)").unwrap(), "add(\n    1,\n    2\n)\n");
+        assert_eq!(format("x = [ 1,[2 ] ] [0] [ -1 ]").unwrap(), "x = [1, [2]][0][-1]\n");
+        assert_eq!(format("[\n1,\n]").unwrap(), "[\n    1,\n]\n");
+        assert_eq!(format("m = { \"a\" : {},\n2:[ 1 ] }").unwrap(), "m = {\"a\": {},\n    2: [1]}\n");
+        assert_eq!(format("x; { print { } ; }").unwrap(), "x;\n{\n    print {};\n}\n");
+        // a brace that starts the source opens a map
+        assert_eq!(format("{} - 1").unwrap(), "{} - 1\n");
+        assert_eq!(format("{ \"a\" : 1 } [ \"a\" ]").unwrap(), "{\"a\": 1}[\"a\"]\n");
+        let interpolated = "\"a${x + {\"b\": 1}}c${\n    \"${y}\"\n}\"\n";
+        assert_eq!(format("\"a${ x+{ \"b\" : 1 } }c${\n\"${ y }\"\n}\"").unwrap(), interpolated);
+    }
+
+    #[test]
+    fn test_keeps_tokens_apart() {
+        // `! =` must not become `!=`
+        assert!(format("! = 1").is_err());
+        assert!(format("\"unterminated").is_err());
+        assert_eq!(format("").unwrap(), "");
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt::{self, Display};
+
+/// A minimal JSON document model used by the tooling renderers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<K: Into<String>>(fields: Vec<(K, Json)>) -> Self {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn parse(source: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: source.char_indices().peekable(), source };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some((pos, _)) => Err(format!("Unexpected trailing characters at {}", pos)),
+        }
+    }
+
+    /// Looks up a field of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    source: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some((_, ' ' | '\t' | '\n' | '\r')) = self.chars.peek() {
+            self.chars.next();
+        }
+    }
+
+    fn position(&mut self) -> usize {
+        self.chars.peek().map(|(pos, _)| *pos).unwrap_or(self.source.len())
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        let pos = self.position();
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            _ => Err(format!("Expected '{}' at {}", expected, pos)),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let pos = self.position();
+        match self.chars.peek().map(|(_, c)| *c) {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if let Some((_, ']')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => continue,
+                        Some((_, ']')) => return Ok(Json::Array(items)),
+                        _ => return Err(format!("Unterminated array starting at {}", pos)),
+                    }
+                }
+            }
+            Some('{') => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if let Some((_, '}')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => continue,
+                        Some((_, '}')) => return Ok(Json::Object(fields)),
+                        _ => return Err(format!("Unterminated object starting at {}", pos)),
+                    }
+                }
+            }
+            Some('-' | '0'..='9') => {
+                let start = pos;
+                while let Some((_, '-' | '+' | '.' | 'e' | 'E' | '0'..='9')) = self.chars.peek() {
+                    self.chars.next();
+                }
+                let end = self.position();
+                self.source[start..end]
+                    .parse()
+                    .map(Json::Number)
+                    .map_err(|_| format!("Invalid number at {}", start))
+            }
+            _ => Err(format!("Unexpected character at {}", pos)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let start = self.position();
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(out),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => out.push('"'),
+                    Some((_, '\\')) => out.push('\\'),
+                    Some((_, '/')) => out.push('/'),
+                    Some((_, 'b')) => out.push('\u{8}'),
+                    Some((_, 'f')) => out.push('\u{c}'),
+                    Some((_, 'n')) => out.push('\n'),
+                    Some((_, 'r')) => out.push('\r'),
+                    Some((_, 't')) => out.push('\t'),
+                    Some((pos, 'u')) => {
+                        let code = self.hex4(pos)?;
+                        // combine surrogate pairs
+                        let code = if (0xd800..0xdc00).contains(&code) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex4(pos)?;
+                            0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+                        } else {
+                            code
+                        };
+                        out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    _ => return Err(format!("Invalid escape in string starting at {}", start)),
+                },
+                Some((_, c)) => out.push(c),
+                None => return Err(format!("Unterminated string starting at {}", start)),
+            }
+        }
+    }
+
+    fn hex4(&mut self, pos: usize) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.chars.next().and_then(|(_, c)| c.to_digit(16));
+            match digit {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(format!("Invalid unicode escape at {}", pos)),
+            }
+        }
+        Ok(code)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no representation for NaN or the infinities
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Json::Number(value)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Number(value as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Json::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let source = r#"{"a":[1,-2.5,1e3],"b":{"c":null,"d":true},"e":"x\"y\n\u00e9"}"#;
+        let json = Json::parse(source).unwrap();
+        assert_eq!(json.get("a").unwrap().as_array().unwrap()[2], Json::Number(1000.0));
+        assert_eq!(json.get("b").unwrap().get("d"), Some(&Json::Bool(true)));
+        assert_eq!(json.get("e").unwrap().as_str(), Some("x\"y\n\u{e9}"));
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Json::parse("{\"a\": }").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("\"abc").is_err());
+        assert!(Json::parse("1 2").is_err());
+    }
+}
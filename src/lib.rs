@@ -0,0 +1,22 @@
+pub mod bench;
+pub mod builtins;
+pub mod opcode;
+pub mod chunk;
+pub mod coverage;
+pub mod dap;
+pub mod debug;
+pub mod debugger;
+pub mod diagnostic;
+pub mod ffi;
+pub mod formatter;
+pub mod json;
+pub mod linter;
+pub mod lsp;
+pub mod optimizer;
+pub mod profiler;
+pub mod value;
+pub mod vm;
+pub mod compiler;
+pub mod scanner;
+pub mod table;
+pub mod verifier;
@@ -0,0 +1,325 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::builtins::is_builtin;
+use crate::scanner::{Scanner, Token, TokenType};
+
+pub const UNUSED_LOCAL: &str = "unused-local";
+pub const UNREACHABLE_CODE: &str = "unreachable-code";
+pub const SHADOWED_VARIABLE: &str = "shadowed-variable";
+pub const SELF_COMPARISON: &str = "self-comparison";
+pub const ASSIGNMENT_IN_CONDITION: &str = "assignment-in-condition";
+pub const UNDEFINED_GLOBAL: &str = "undefined-global";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    /// 1-based, counted in characters.
+    pub column: usize,
+    /// One of the stable codes above, which `// lox-allow: code` suppresses.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: warning[{}]: {}", self.line, self.column, self.code, self.message)
+    }
+}
+
+struct Local<'src> {
+    token: Token<'src>,
+    used: bool,
+}
+
+/// Checks a program for common mistakes without running it.
+///
+/// The analysis works on the token stream and understands the statement
+/// syntax the scanner knows about (`var`, `fun`, blocks, `if`, `while` and
+/// `return`), not only what the compiler accepts today. A `// lox-allow: code`
+/// comment (several codes may be separated by commas) silences warnings on the
+/// line it ends, or on the next line when it stands on a line of its own.
+pub fn lint(source: &str) -> Result<Vec<Warning>, String> {
+    let mut allowed: HashMap<usize, Vec<&str>> = HashMap::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut scanner = Scanner::with_comments(source);
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Eof => break,
+            TokenType::Error => return Err(format!("[line {}] Error: {}", token.line, token.lexeme)),
+            TokenType::Comment => {
+                if let Some(codes) = token.lexeme.trim_start_matches('/').trim().strip_prefix("lox-allow:") {
+                    // a comment after code covers its own line, one on a line of its own the next
+                    let trailing = tokens.last().is_some_and(|last| last.line == token.line);
+                    let line = if trailing { token.line } else { token.line + 1 };
+                    allowed.entry(line).or_default().extend(codes.split(',').map(str::trim));
+                }
+            }
+            _ => tokens.push(token),
+        }
+    }
+
+    let mut linter = Linter { source, tokens: &tokens, warnings: Vec::new() };
+    linter.run();
+    let mut warnings = linter.warnings;
+    warnings.retain(|warning| !allowed.get(&warning.line).is_some_and(|codes| codes.contains(&warning.code)));
+    warnings.sort_by_key(|warning| (warning.line, warning.column));
+    Ok(warnings)
+}
+
+struct Linter<'a, 'src> {
+    source: &'src str,
+    tokens: &'a [Token<'src>],
+    warnings: Vec<Warning>,
+}
+
+impl<'src> Linter<'_, 'src> {
+    fn warn(&mut self, token: &Token, code: &'static str, message: String) {
+        let line_start = self.source[..token.offset].rfind('\n').map_or(0, |n| n + 1);
+        let column = self.source[line_start..token.offset].chars().count() + 1;
+        self.warnings.push(Warning { line: token.line, column, code, message });
+    }
+
+    fn token_type(&self, index: usize) -> Option<TokenType> {
+        self.tokens.get(index).map(|token| token.token_type)
+    }
+
+    fn run(&mut self) {
+        let tokens = self.tokens;
+        let mut scopes: Vec<Vec<Local<'src>>> = Vec::new();
+        let mut globals: HashSet<&str> = HashSet::new();
+        let mut calls: Vec<Token<'src>> = Vec::new();
+        // parameters waiting for the body of the function being declared
+        let mut parameters: Vec<Token<'src>> = Vec::new();
+
+        for (index, token) in tokens.iter().enumerate() {
+            let previous = index.checked_sub(1).and_then(|i| self.token_type(i));
+            match token.token_type {
+                TokenType::LeftBrace => {
+                    let scope = parameters.drain(..).map(|token| Local { token, used: true }).collect();
+                    scopes.push(scope);
+                }
+                TokenType::RightBrace => {
+                    for local in scopes.pop().unwrap_or_default() {
+                        if !local.used && !local.token.lexeme.starts_with('_') {
+                            let message = format!("Local variable '{}' is never used", local.token.lexeme);
+                            self.warn(&local.token, UNUSED_LOCAL, message);
+                        }
+                    }
+                }
+                TokenType::Identifier => match previous {
+                    Some(TokenType::Var | TokenType::Fun | TokenType::Class) => {
+                        if let Some(outer) = scopes
+                            .iter()
+                            .rev()
+                            .skip(1)
+                            .flatten()
+                            .find(|local| local.token.lexeme == token.lexeme)
+                        {
+                            let message = format!(
+                                "'{}' shadows the variable declared on line {}",
+                                token.lexeme, outer.token.line
+                            );
+                            self.warn(token, SHADOWED_VARIABLE, message);
+                        }
+                        match scopes.last_mut() {
+                            Some(scope) => scope.push(Local { token: *token, used: false }),
+                            None => {
+                                globals.insert(token.lexeme);
+                            }
+                        }
+                        if previous == Some(TokenType::Fun) {
+                            parameters = self.parameters(index + 1);
+                        }
+                    }
+                    Some(TokenType::Dot) => {}
+                    _ => {
+                        let local = scopes.iter_mut().rev().flatten().find(|local| local.token.lexeme == token.lexeme);
+                        match local {
+                            Some(local) => local.used = true,
+                            None if self.token_type(index + 1) == Some(TokenType::Equal) => {
+                                // assigning at the top level defines a global
+                                globals.insert(token.lexeme);
+                            }
+                            None if self.token_type(index + 1) == Some(TokenType::LeftParen) => calls.push(*token),
+                            None => {}
+                        }
+                    }
+                },
+                TokenType::Return => self.check_unreachable(index),
+                TokenType::If | TokenType::While => self.check_condition(index),
+                TokenType::EqualEqual
+                | TokenType::BangEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual => self.check_self_comparison(index),
+                _ => {}
+            }
+        }
+
+        for call in calls {
+            if !globals.contains(call.lexeme) && !is_builtin(call.lexeme) {
+                self.warn(&call, UNDEFINED_GLOBAL, format!("Call to undefined global '{}'", call.lexeme));
+            }
+        }
+    }
+
+    /// The parameter names of the function whose `(` is at `start`.
+    fn parameters(&self, start: usize) -> Vec<Token<'src>> {
+        if self.token_type(start) != Some(TokenType::LeftParen) {
+            return Vec::new();
+        }
+        self.tokens[start + 1..]
+            .iter()
+            .take_while(|token| token.token_type != TokenType::RightParen)
+            .filter(|token| token.token_type == TokenType::Identifier)
+            .copied()
+            .collect()
+    }
+
+    /// Warns about the first statement after `return ...;` in the same block.
+    fn check_unreachable(&mut self, index: usize) {
+        let mut depth: usize = 0;
+        for (offset, token) in self.tokens[index + 1..].iter().enumerate() {
+            match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen => depth = depth.saturating_sub(1),
+                TokenType::RightBrace if depth == 0 => return,
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Semicolon if depth == 0 => {
+                    let next = index + 1 + offset + 1;
+                    if let Some(next) = self.tokens.get(next) {
+                        if next.token_type != TokenType::RightBrace {
+                            self.warn(next, UNREACHABLE_CODE, "Unreachable code after 'return'".to_string());
+                        }
+                    }
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Warns about `=` directly inside the parentheses after `if` or `while`.
+    fn check_condition(&mut self, index: usize) {
+        if self.token_type(index + 1) != Some(TokenType::LeftParen) {
+            return;
+        }
+        let mut depth: usize = 0;
+        for token in &self.tokens[index + 1..] {
+            match token.token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen if depth == 1 => return,
+                TokenType::RightParen => depth -= 1,
+                TokenType::Equal if depth == 1 => {
+                    let message = "Assignment in condition; did you mean '=='?".to_string();
+                    self.warn(token, ASSIGNMENT_IN_CONDITION, message);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Warns about `x == x` and the like when both operands are the same bare variable.
+    fn check_self_comparison(&mut self, index: usize) {
+        let (Some(left), Some(right)) = (index.checked_sub(1).map(|i| &self.tokens[i]), self.tokens.get(index + 1))
+        else {
+            return;
+        };
+        if left.token_type != TokenType::Identifier
+            || right.token_type != TokenType::Identifier
+            || left.lexeme != right.lexeme
+        {
+            return;
+        }
+        // anything binding tighter than the comparison would make an operand more than the bare name
+        let before = index.checked_sub(2).and_then(|i| self.token_type(i));
+        let after = self.token_type(index + 2);
+        let left_bare = matches!(
+            before,
+            None | Some(
+                TokenType::LeftParen
+                    | TokenType::Comma
+                    | TokenType::Equal
+                    | TokenType::Semicolon
+                    | TokenType::LeftBrace
+                    | TokenType::RightBrace
+                    | TokenType::And
+                    | TokenType::Or
+                    | TokenType::Return
+                    | TokenType::Print
+            )
+        );
+        let right_bare = matches!(
+            after,
+            None | Some(
+                TokenType::RightParen
+                    | TokenType::Comma
+                    | TokenType::Semicolon
+                    | TokenType::RightBrace
+                    | TokenType::And
+                    | TokenType::Or
+            )
+        );
+        if left_bare && right_bare {
+            let token = self.tokens[index - 1];
+            self.warn(&token, SELF_COMPARISON, format!("Comparing '{}' to itself", left.lexeme));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(source: &str) -> Vec<(usize, usize, &'static str)> {
+        lint(source).unwrap().iter().map(|warning| (warning.line, warning.column, warning.code)).collect()
+    }
+
+    #[test]
+    fn test_warnings() {
+        let source = "\
+fun f(a) {
+  var unused = 1;
+  var b = a;
+  {
+    var b = 2;
+    print b;
+  }
+  if (b = 3) print b == b;
+  return b;
+  print missing(1);
+}
+f(1);
+print 1 + x == x;
+";
+        assert_eq!(
+            codes(source),
+            vec![
+                (2, 7, UNUSED_LOCAL),
+                (5, 9, SHADOWED_VARIABLE),
+                (8, 9, ASSIGNMENT_IN_CONDITION),
+                (8, 20, SELF_COMPARISON),
+                (10, 3, UNREACHABLE_CODE),
+                (10, 9, UNDEFINED_GLOBAL),
+            ]
+        );
+        let warning = &lint(source).unwrap()[0];
+        assert_eq!(warning.to_string(), "2:7: warning[unused-local]: Local variable 'unused' is never used");
+    }
+
+    #[test]
+    fn test_suppression() {
+        let source = "\
+total = 0
+  // lox-allow: undefined-global, self-comparison
+  + add(total == total)
+  + sub(1) // lox-allow: undefined-global
+  + mul(2)
+  + len([])
+";
+        assert_eq!(codes(source), vec![(5, 5, UNDEFINED_GLOBAL)]);
+    }
+}
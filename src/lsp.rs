@@ -0,0 +1,508 @@
+//! A Language Server Protocol server for Lox, spoken over stdin/stdout.
+//!
+//! Documents are synced in full. The server publishes compile errors as
+//! diagnostics and answers semantic token, go-to-definition and hover requests.
+//! Since globals are the only variables, a name's definition is its first
+//! assignment in the document; functions are built in or defined by the host and have none.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::builtins::is_builtin;
+use crate::compiler::compile;
+use crate::json::Json;
+use crate::scanner::{Scanner, Token, TokenType};
+
+/// The semantic token types, in the order the indices in responses refer to.
+pub const TOKEN_TYPES: [&str; 6] = ["keyword", "variable", "function", "string", "number", "comment"];
+
+const PARSE_ERROR: f64 = -32700.0;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const INVALID_PARAMS: f64 = -32602.0;
+
+/// Reads one `Content-Length` framed message, or `None` at the end of input.
+/// A body that is not valid JSON is returned as `Err` with the reason, since
+/// the framing is intact and the next message can still be read.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Result<Json, String>>> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let message = match String::from_utf8(body) {
+        Ok(body) => Json::parse(&body),
+        Err(err) => Err(err.to_string()),
+    };
+    Ok(Some(message))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serves requests until the client sends `exit`, returning the process exit code.
+pub fn run(mut input: impl BufRead, mut output: impl Write) -> io::Result<i32> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        let message = match message {
+            Ok(message) => message,
+            Err(msg) => {
+                write_message(&mut output, &parse_error(&msg))?;
+                continue;
+            }
+        };
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+        if let Some(code) = server.exit_code {
+            return Ok(code);
+        }
+    }
+    // the client went away without asking us to exit
+    Ok(1)
+}
+
+/// The reply to a message that could not be parsed, whose id is unknown.
+fn parse_error(message: &str) -> Json {
+    let error = Json::object(vec![("code", PARSE_ERROR.into()), ("message", format!("Parse error: {}", message).into())]);
+    Json::object(vec![("jsonrpc", "2.0".into()), ("id", Json::Null), ("error", error)])
+}
+
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exit_code: Option<i32>,
+}
+
+impl Server {
+    /// Handles one request or notification, returning the messages to send back.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let Some(id) = message.get("id") else {
+            return self.notification(method, params);
+        };
+        let result = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/semanticTokens/full" => self.with_document(params, |source, _| semantic_tokens(source)),
+            "textDocument/definition" => self.with_document(params, |source, params| {
+                let uri = params.get("textDocument").and_then(|doc| doc.get("uri")).cloned().unwrap_or(Json::Null);
+                match definition(source, position_param(source, params)?) {
+                    Some((start, end)) => Ok(Json::object(vec![("uri", uri), ("range", range(source, start, end))])),
+                    None => Ok(Json::Null),
+                }
+            }),
+            "textDocument/hover" => self.with_document(params, |source, params| {
+                Ok(match hover(source, position_param(source, params)?) {
+                    Some(value) => Json::object(vec![(
+                        "contents",
+                        Json::object(vec![("kind", "markdown".into()), ("value", value.into())]),
+                    )]),
+                    None => Json::Null,
+                })
+            }),
+            _ => Err((METHOD_NOT_FOUND, format!("Unhandled method '{}'", method))),
+        };
+        let reply = match result {
+            Ok(result) => ("result", result),
+            Err((code, message)) => {
+                ("error", Json::object(vec![("code", code.into()), ("message", message.into())]))
+            }
+        };
+        vec![Json::object(vec![("jsonrpc", "2.0".into()), ("id", id.clone()), reply])]
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let Some(uri) = params.get("textDocument").and_then(|doc| doc.get("uri")).and_then(Json::as_str) else {
+            if method == "exit" {
+                self.exit_code = Some(if self.shutdown { 0 } else { 1 });
+            }
+            return Vec::new();
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|doc| doc.get("text")).and_then(Json::as_str);
+                self.documents.insert(uri.to_string(), text.unwrap_or("").to_string());
+            }
+            "textDocument/didChange" => {
+                // full sync: the last change holds the whole document
+                let changes = params.get("contentChanges").and_then(Json::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|change| change.get("text")).and_then(Json::as_str) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            _ => return Vec::new(),
+        }
+        // a change without text for a document that was never opened leaves nothing to check
+        match self.documents.get(uri) {
+            Some(source) => vec![publish_diagnostics(uri, diagnostics(source))],
+            None => Vec::new(),
+        }
+    }
+
+    fn with_document(
+        &self,
+        params: &Json,
+        f: impl FnOnce(&str, &Json) -> Result<Json, (f64, String)>,
+    ) -> Result<Json, (f64, String)> {
+        let uri = params.get("textDocument").and_then(|doc| doc.get("uri")).and_then(Json::as_str);
+        match uri.and_then(|uri| self.documents.get(uri)) {
+            Some(source) => f(source, params),
+            None => Err((INVALID_PARAMS, "Unknown document".to_string())),
+        }
+    }
+}
+
+fn capabilities() -> Json {
+    let legend = Json::object(vec![
+        ("tokenTypes", Json::Array(TOKEN_TYPES.iter().map(|&name| name.into()).collect())),
+        ("tokenModifiers", Json::Array(Vec::new())),
+    ]);
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                ("textDocumentSync", 1.0.into()),
+                ("hoverProvider", true.into()),
+                ("definitionProvider", true.into()),
+                ("semanticTokensProvider", Json::object(vec![("legend", legend), ("full", true.into())])),
+            ]),
+        ),
+        ("serverInfo", Json::object(vec![("name", "rlox".into())])),
+    ])
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        ("params", Json::object(vec![("uri", uri.into()), ("diagnostics", Json::Array(diagnostics))])),
+    ])
+}
+
+/// One error diagnostic per compile error, covering the span it was reported at.
+fn diagnostics(source: &str) -> Vec<Json> {
+    let Err(errors) = compile(source) else {
+        return Vec::new();
+    };
+    errors
+        .iter()
+        .map(|error| {
+            let start = error.span.offset.min(source.len());
+            let end = (error.span.offset + error.span.length).min(source.len());
+            let mut message = error.message.clone();
+            for note in &error.notes {
+                message.push_str(&format!("\nnote: {}", note));
+            }
+            if let Some(help) = &error.help {
+                message.push_str(&format!("\nhelp: {}", help));
+            }
+            Json::object(vec![
+                ("range", range(source, start, end)),
+                ("severity", 1.0.into()),
+                ("source", "rlox".into()),
+                ("message", message.into()),
+            ])
+        })
+        .collect()
+}
+
+fn tokens(source: &str) -> Vec<Token<'_>> {
+    let mut scanner = Scanner::with_comments(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        if token.token_type == TokenType::Eof {
+            return tokens;
+        }
+        tokens.push(token);
+    }
+}
+
+/// The byte range a token covers.
+fn token_span(token: &Token) -> (usize, usize) {
+    (token.offset, token.offset + token.length)
+}
+
+fn token_kind(tokens: &[Token], index: usize) -> Option<usize> {
+    let kind = match tokens[index].token_type {
+        TokenType::Identifier => match tokens.get(index + 1) {
+            Some(next) if next.token_type == TokenType::LeftParen => "function",
+            _ => "variable",
+        },
+        TokenType::String | TokenType::Interpolation => "string",
+        TokenType::Number => "number",
+        TokenType::Comment => "comment",
+        TokenType::And
+        | TokenType::Class
+        | TokenType::Else
+        | TokenType::False
+        | TokenType::For
+        | TokenType::Fun
+        | TokenType::If
+        | TokenType::Nil
+        | TokenType::Or
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Super
+        | TokenType::This
+        | TokenType::True
+        | TokenType::Var
+        | TokenType::While => "keyword",
+        _ => return None,
+    };
+    TOKEN_TYPES.iter().position(|&name| name == kind)
+}
+
+/// Encodes tokens as LSP's relative `[line, start, length, type, modifiers]` quintuples.
+fn semantic_tokens(source: &str) -> Result<Json, (f64, String)> {
+    let tokens = tokens(source);
+    let mut data: Vec<Json> = Vec::new();
+    let (mut last_line, mut last_start) = (0, 0);
+    for index in 0..tokens.len() {
+        let Some(kind) = token_kind(&tokens, index) else {
+            continue;
+        };
+        let (start, end) = token_span(&tokens[index]);
+        // a token spanning lines (a multi-line string) is sent one line at a time
+        let mut segment_start = start;
+        for segment in source[start..end].split('\n') {
+            let (line, character) = position(source, segment_start);
+            let delta_start = if line == last_line { character - last_start } else { character };
+            let length: usize = segment.encode_utf16().count();
+            if length > 0 {
+                for n in [line - last_line, delta_start, length, kind, 0] {
+                    data.push(n.into());
+                }
+                (last_line, last_start) = (line, character);
+            }
+            segment_start += segment.len() + 1;
+        }
+    }
+    Ok(Json::object(vec![("data", Json::Array(data))]))
+}
+
+/// The identifier token under the cursor, if any.
+fn identifier_at<'src>(source: &'src str, offset: usize) -> Option<(Vec<Token<'src>>, usize)> {
+    let tokens = tokens(source);
+    let index = tokens.iter().position(|token| {
+        let (start, end) = token_span(token);
+        token.token_type == TokenType::Identifier && start <= offset && offset <= end
+    })?;
+    Some((tokens, index))
+}
+
+/// The first assignment to `name`, as `(name start, name end)` byte offsets.
+fn find_definition(tokens: &[Token], name: &str) -> Option<(usize, usize)> {
+    tokens.windows(2).find_map(|pair| {
+        let defines = pair[0].token_type == TokenType::Identifier
+            && pair[0].lexeme == name
+            && pair[1].token_type == TokenType::Equal;
+        defines.then(|| token_span(&pair[0]))
+    })
+}
+
+fn definition(source: &str, offset: usize) -> Option<(usize, usize)> {
+    let (tokens, index) = identifier_at(source, offset)?;
+    find_definition(&tokens, tokens[index].lexeme)
+}
+
+fn hover(source: &str, offset: usize) -> Option<String> {
+    let (tokens, index) = identifier_at(source, offset)?;
+    let name = tokens[index].lexeme;
+    if let Some((start, _)) = find_definition(&tokens, name) {
+        let (line, _) = position(source, start);
+        let (line_start, line_end) = line_bounds(source, line + 1);
+        return Some(format!(
+            "global variable `{}`\n\nassigned on line {}:\n```lox\n{}\n```",
+            name,
+            line + 1,
+            source[line_start..line_end].trim()
+        ));
+    }
+    let called = tokens.get(index + 1).is_some_and(|next| next.token_type == TokenType::LeftParen);
+    Some(if is_builtin(name) {
+        format!("function `{}`, built in", name)
+    } else if called {
+        format!("function `{}`, defined by the host", name)
+    } else {
+        format!("global `{}`, never assigned in this file", name)
+    })
+}
+
+/// The byte offsets of the start and end (excluding the newline) of a 1-based line.
+fn line_bounds(source: &str, line: usize) -> (usize, usize) {
+    let start: usize = source.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+    let start = start.min(source.len());
+    let end = source[start..].find('\n').map_or(source.len(), |n| start + n);
+    (start, end)
+}
+
+/// Converts a byte offset to an LSP position: a 0-based line and a UTF-16 column.
+pub fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+    (line, before[line_start..].encode_utf16().count())
+}
+
+/// Converts an LSP position back to a byte offset, clamping to the end of the line.
+pub fn offset(source: &str, line: usize, character: usize) -> usize {
+    let (start, end) = line_bounds(source, line + 1);
+    let mut units = 0;
+    for (index, c) in source[start..end].char_indices() {
+        if units >= character {
+            return start + index;
+        }
+        units += c.len_utf16();
+    }
+    end
+}
+
+/// The byte offset of a request's `position` parameter.
+fn position_param(source: &str, params: &Json) -> Result<usize, (f64, String)> {
+    let field = |name| params.get("position").and_then(|position| position.get(name)).and_then(Json::as_f64);
+    match (field("line"), field("character")) {
+        (Some(line), Some(character)) => Ok(offset(source, line as usize, character as usize)),
+        _ => Err((INVALID_PARAMS, "Missing position".to_string())),
+    }
+}
+
+fn range(source: &str, start: usize, end: usize) -> Json {
+    let point = |offset| {
+        let (line, character) = position(source, offset);
+        Json::object(vec![("line", line.into()), ("character", character.into())])
+    };
+    Json::object(vec![("start", point(start)), ("end", point(end))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames each message, runs a whole session and returns everything the server sent.
+    fn session(messages: &[&str]) -> (i32, Vec<Json>) {
+        let mut input = Vec::new();
+        for message in messages {
+            write!(input, "Content-Length: {}\r\n\r\n{}", message.len(), message).unwrap();
+        }
+        let mut output = Vec::new();
+        let code = run(&input[..], &mut output).unwrap();
+        let mut output = &output[..];
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut output).unwrap() {
+            replies.push(reply.unwrap());
+        }
+        (code, replies)
+    }
+
+    fn numbers(json: &Json) -> Vec<usize> {
+        json.as_array().unwrap().iter().map(|n| n.as_f64().unwrap() as usize).collect()
+    }
+
+    #[test]
+    fn test_session() {
+        let (code, replies) = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.lox","languageId":"lox","version":1,"text":"x = (1 +"}}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.lox","version":2},"contentChanges":[{"text":"total = add(\"é\", total) // sum"}]}}"#,
+            // a change without text to a document that was never opened is ignored
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///b.lox","version":1},"contentChanges":[]}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/semanticTokens/full","params":{"textDocument":{"uri":"file:///a.lox"}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":0,"character":19}}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":0,"character":9}}}"#,
+            r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/rename","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":6,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]);
+        assert_eq!(code, 0);
+        assert_eq!(replies.len(), 8);
+
+        let legend = replies[0].get("result").unwrap().get("capabilities").unwrap().get("semanticTokensProvider");
+        assert!(legend.is_some());
+
+        let diagnostics = replies[1].get("params").unwrap().get("diagnostics").unwrap().as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].get("message").unwrap().as_str(), Some("Expect expression"));
+        // the error at the end of the input points just past the '+'
+        let start = diagnostics[0].get("range").unwrap().get("start").unwrap();
+        assert_eq!(start.get("character").unwrap().as_f64(), Some(8.0));
+        let cleared = replies[2].get("params").unwrap().get("diagnostics").unwrap();
+        assert_eq!(cleared, &Json::Array(Vec::new()));
+
+        // total (variable), add (function), "é" (string), total (variable), the comment
+        let data = numbers(replies[3].get("result").unwrap().get("data").unwrap());
+        assert_eq!(data, vec![0, 0, 5, 1, 0, 0, 8, 3, 2, 0, 0, 4, 3, 3, 0, 0, 5, 5, 1, 0, 0, 7, 6, 5, 0]);
+
+        let definition = replies[4].get("result").unwrap();
+        assert_eq!(definition.get("uri").unwrap().as_str(), Some("file:///a.lox"));
+        let end = definition.get("range").unwrap().get("end").unwrap();
+        assert_eq!(end.get("character").unwrap().as_f64(), Some(5.0));
+
+        let hover = replies[5].get("result").unwrap().get("contents").unwrap().get("value").unwrap();
+        assert_eq!(hover.as_str(), Some("function `add`, defined by the host"));
+
+        assert_eq!(replies[6].get("error").unwrap().get("code").unwrap().as_f64(), Some(METHOD_NOT_FOUND));
+        assert_eq!(replies[7].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        let (code, replies) = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]);
+        // the server answers the bad message and keeps serving
+        assert_eq!(code, 0);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].get("id"), Some(&Json::Null));
+        assert_eq!(replies[0].get("error").unwrap().get("code").unwrap().as_f64(), Some(PARSE_ERROR));
+        assert_eq!(replies[1].get("id").unwrap().as_f64(), Some(2.0));
+
+        let mut input = &b"Content-Type: x\r\n\r\n{}"[..];
+        assert!(run(&mut input, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_exit_without_shutdown() {
+        let (code, replies) = session(&[r#"{"jsonrpc":"2.0","method":"exit"}"#]);
+        assert_eq!(code, 1);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn test_positions_count_utf16_units() {
+        let source = "a\n\"😀\" + b";
+        let b = source.find('b').unwrap();
+        assert_eq!(position(source, b), (1, 7));
+        assert_eq!(offset(source, 1, 7), b);
+        assert_eq!(offset(source, 5, 0), source.len());
+    }
+}
@@ -1,23 +1,34 @@
-#![feature(downcast_unchecked)]
+use std::{env, io::{IsTerminal, Write}, path::Path};
 
-use std::{env, io::Write};
+use lox::bench::{from_json, load_benchmarks, regressions, report, run_benchmark, to_json};
+use lox::chunk::{is_serialized, Chunk};
+use lox::compiler::{compile, CompileError};
+use lox::debug::{disassemble_to_json, disassemble_to_string};
+use lox::debugger::Debugger;
+use lox::diagnostic::Renderer;
+use lox::formatter::format;
+use lox::json::Json;
+use lox::linter::lint;
+use lox::vm::VM;
 
-use vm::VM;
+const VERSION: &str = "0.0.1";
 
-mod opcode;
-mod chunk;
-#[cfg(feature = "debug")]
-mod debug;
-mod value;
-mod vm;
-mod compiler;
-mod scanner;
+/// Renders errors with source excerpts, colored when stderr is a terminal and `NO_COLOR` is unset.
+fn renderer(path: &str) -> Renderer {
+    let color = std::io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    Renderer::new(path, color)
+}
 
-const VERSION: &str = "0.0.1";
+fn report_compile_errors(path: &str, source: &str, errors: &[CompileError]) {
+    let renderer = renderer(path);
+    for error in errors {
+        eprint!("{}", renderer.render(&error.diagnostic(), source));
+    }
+}
 
 fn repl() {
-    let mut line = String::new();
     let mut vm = VM::default();
+    vm.set_renderer(renderer("<stdin>"));
     loop {
         print!("> ");
         std::io::stdout().flush().expect("Failed to flush stdout");
@@ -28,20 +39,254 @@ fn repl() {
 }
 
 fn run_file(path: &str) {
-    let contents = std::fs::read_to_string(path).expect("Failed to read file");
     let mut vm = VM::default();
-    vm.interpret(&contents);
+    run_file_with(&mut vm, path);
+}
+
+/// Runs the file with profiling enabled, then prints the report to stderr
+/// or writes collapsed stacks to `collapsed` if given.
+fn profile_file(path: &str, collapsed: Option<&str>) {
+    let mut vm = VM::default();
+    vm.enable_profiler();
+    run_file_with(&mut vm, path);
+    let profiler = vm.profiler().unwrap();
+    match collapsed {
+        Some(output) => std::fs::write(output, profiler.collapsed_stacks()).expect("Failed to write file"),
+        None => eprint!("{}", profiler.report()),
+    }
+}
+
+/// Runs the file recording line coverage, then writes an lcov tracefile and prints a summary.
+fn cover_file(path: &str, output: &str) {
+    let mut vm = VM::default();
+    vm.enable_coverage();
+    run_file_with(&mut vm, path);
+    let coverage = vm.coverage().unwrap();
+    std::fs::write(output, coverage.to_lcov(path)).expect("Failed to write file");
+    eprint!("{}", coverage.summary(path));
+}
+
+/// Runs the file under the command-line debugger, which stops before the first line.
+fn debug_file(path: &str) {
+    let source = std::fs::read_to_string(path).unwrap_or_default();
+    let mut vm = VM::default();
+    vm.set_debug_hook(Debugger::new(std::io::stdin().lock(), std::io::stdout(), &source));
+    run_file_with(&mut vm, path);
+}
+
+fn run_file_with(vm: &mut VM, path: &str) {
+    vm.set_renderer(renderer(path));
+    let bytes = std::fs::read(path).expect("Failed to read file");
+    if is_serialized(&bytes) {
+        match Chunk::deserialize(&bytes) {
+            Ok(chunk) => vm.interpret_chunk(chunk),
+            Err(msg) => {
+                eprintln!("{}: {}", path, msg);
+                std::process::exit(65);
+            }
+        };
+    } else {
+        let contents = String::from_utf8(bytes).expect("File is not valid UTF-8");
+        vm.interpret(&contents);
+    }
+}
+
+fn compile_file(path: &str, output: &str) {
+    let contents = std::fs::read_to_string(path).expect("Failed to read file");
+    let chunk = match compile(&contents) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            report_compile_errors(path, &contents, &errors);
+            std::process::exit(65);
+        }
+    };
+    match chunk.serialize() {
+        Ok(bytes) => std::fs::write(output, bytes).expect("Failed to write file"),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(65);
+        }
+    }
+}
+
+fn disassemble_file(path: &str, json: bool) {
+    let contents = std::fs::read_to_string(path).expect("Failed to read file");
+    let chunk = match compile(&contents) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            report_compile_errors(path, &contents, &errors);
+            std::process::exit(65);
+        }
+    };
+    let listing = if json {
+        disassemble_to_json(&chunk).map(|json| format!("{}\n", json))
+    } else {
+        disassemble_to_string(&chunk)
+    };
+    match listing {
+        Ok(listing) => print!("{}", listing),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(70);
+        }
+    }
+}
+
+/// Rewrites each file in the canonical style, or with `check` only reports the
+/// files that would change.
+fn format_files(paths: &[&str], check: bool) {
+    if paths.is_empty() {
+        usage();
+    }
+    let mut failed = false;
+    let mut unformatted = false;
+    for path in paths {
+        let contents = std::fs::read_to_string(path).expect("Failed to read file");
+        match format(&contents) {
+            Ok(formatted) if formatted == contents => {}
+            Ok(_) if check => {
+                println!("{}: not formatted", path);
+                unformatted = true;
+            }
+            Ok(formatted) => std::fs::write(path, formatted).expect("Failed to write file"),
+            Err(msg) => {
+                eprintln!("{}: {}", path, msg);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(65);
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+/// Prints the warnings for each file, exiting with 1 if there were any.
+fn lint_files(paths: &[&str]) {
+    if paths.is_empty() {
+        usage();
+    }
+    let mut warned = false;
+    for path in paths {
+        let contents = std::fs::read_to_string(path).expect("Failed to read file");
+        match lint(&contents) {
+            Ok(warnings) => {
+                for warning in &warnings {
+                    println!("{}:{}", path, warning);
+                }
+                warned |= !warnings.is_empty();
+            }
+            Err(msg) => {
+                eprintln!("{}: {}", path, msg);
+                std::process::exit(65);
+            }
+        }
+    }
+    if warned {
+        std::process::exit(1);
+    }
+}
+
+fn serve_dap() {
+    if let Err(err) = lox::dap::run(std::io::stdin().lock(), std::io::stdout()) {
+        eprintln!("dap: {}", err);
+        std::process::exit(74);
+    }
+}
+
+fn serve_lsp() {
+    let stdin = std::io::stdin();
+    match lox::lsp::run(stdin.lock(), std::io::stdout()) {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            eprintln!("lsp: {}", err);
+            std::process::exit(74);
+        }
+    }
+}
+
+fn bench(args: &[&str]) {
+    let mut runs: usize = 10;
+    let mut dir: &str = "bench";
+    let mut save: Option<&str> = None;
+    let mut baseline: Option<&str> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--runs" => runs = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            "--save" => save = Some(args.next().unwrap_or_else(|| usage())),
+            "--baseline" => baseline = Some(args.next().unwrap_or_else(|| usage())),
+            path => dir = path,
+        }
+    }
+
+    let benchmarks = load_benchmarks(Path::new(dir)).expect("Failed to read benchmark directory");
+    let results: Vec<_> = benchmarks.iter().map(|benchmark| run_benchmark(benchmark, runs)).collect();
+    print!("{}", report(&results));
+
+    if let Some(path) = save {
+        std::fs::write(path, format!("{}\n", to_json(&results))).expect("Failed to write file");
+    }
+    if let Some(path) = baseline {
+        let contents = std::fs::read_to_string(path).expect("Failed to read file");
+        let baseline = match Json::parse(&contents).and_then(|json| from_json(&json)) {
+            Ok(baseline) => baseline,
+            Err(msg) => {
+                eprintln!("{}: {}", path, msg);
+                std::process::exit(65);
+            }
+        };
+        let found = regressions(&results, &baseline, 0.1);
+        for regression in &found {
+            eprintln!("regression: {}", regression);
+        }
+        if !found.is_empty() {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: rlox [path]");
+    eprintln!("       rlox --profile [--collapsed <output>] <path>");
+    eprintln!("       rlox --coverage <lcov-output> <path>");
+    eprintln!("       rlox compile <path> [-o <output>]");
+    eprintln!("       rlox disasm [--json] <path>");
+    eprintln!("       rlox fmt [--check] <path>...");
+    eprintln!("       rlox debug <path>");
+    eprintln!("       rlox lint <path>...");
+    eprintln!("       rlox lsp");
+    eprintln!("       rlox dap");
+    eprintln!("       rlox header");
+    eprintln!("       rlox bench [--runs <n>] [--save <file>] [--baseline <file>] [dir]");
+    std::process::exit(64);
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let args: Vec<&str> = args.iter().skip(1).map(String::as_str).collect();
 
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
-    } else {
-        eprintln!("Usage: rlox [path]");
-        std::process::exit(64);
+    match args.as_slice() {
+        [] => repl(),
+        ["--version"] => println!("rlox {}", VERSION),
+        ["fmt", "--check", paths @ ..] => format_files(paths, true),
+        ["fmt", paths @ ..] => format_files(paths, false),
+        ["debug", path] => debug_file(path),
+        ["lint", paths @ ..] => lint_files(paths),
+        ["lsp"] => serve_lsp(),
+        ["dap"] => serve_dap(),
+        ["header"] => print!("{}", lox::ffi::header()),
+        ["compile", path] => compile_file(path, &Path::new(path).with_extension("loxc").to_string_lossy()),
+        ["compile", path, "-o", output] => compile_file(path, output),
+        ["--profile", path] => profile_file(path, None),
+        ["--profile", "--collapsed", output, path] => profile_file(path, Some(output)),
+        ["--coverage", output, path] => cover_file(path, output),
+        ["bench", rest @ ..] => bench(rest),
+        ["disasm", path] => disassemble_file(path, false),
+        ["disasm", "--json", path] => disassemble_file(path, true),
+        [path] => run_file(path),
+        _ => usage(),
     }
 }
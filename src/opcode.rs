@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
@@ -16,10 +16,162 @@ pub enum OpCode {
     Modulo,
     Negate,
     Return,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    AddConstant,
+    LessConstant,
+    GetGlobal,
+    SetGlobal,
+    Call,
+    BuildList,
+    IndexGet,
+    IndexSet,
+    BuildMap,
+    Stringify,
 }
 
-impl From<u8> for OpCode {
-    fn from(value: u8) -> Self {
-        unsafe { std::mem::transmute(value) }
+impl OpCode {
+    /// Every opcode, indexed by its byte value.
+    pub const ALL: [OpCode; 28] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Modulo,
+        OpCode::Negate,
+        OpCode::Return,
+        OpCode::NotEqual,
+        OpCode::GreaterEqual,
+        OpCode::LessEqual,
+        OpCode::AddConstant,
+        OpCode::LessConstant,
+        OpCode::GetGlobal,
+        OpCode::SetGlobal,
+        OpCode::Call,
+        OpCode::BuildList,
+        OpCode::IndexGet,
+        OpCode::IndexSet,
+        OpCode::BuildMap,
+        OpCode::Stringify,
+    ];
+
+    /// The mnemonic used by the disassembler.
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Constant => "CONSTANT",
+            OpCode::Nil => "NIL",
+            OpCode::True => "TRUE",
+            OpCode::False => "FALSE",
+            OpCode::Equal => "EQUAL",
+            OpCode::Greater => "GREATER",
+            OpCode::Less => "LESS",
+            OpCode::Add => "ADD",
+            OpCode::Subtract => "SUBTRACT",
+            OpCode::Multiply => "MULTIPLY",
+            OpCode::Divide => "DIVIDE",
+            OpCode::Not => "NOT",
+            OpCode::Modulo => "MODULO",
+            OpCode::Negate => "NEGATE",
+            OpCode::Return => "RETURN",
+            OpCode::NotEqual => "NOT_EQUAL",
+            OpCode::GreaterEqual => "GREATER_EQUAL",
+            OpCode::LessEqual => "LESS_EQUAL",
+            OpCode::AddConstant => "ADD_CONSTANT",
+            OpCode::LessConstant => "LESS_CONSTANT",
+            OpCode::GetGlobal => "GET_GLOBAL",
+            OpCode::SetGlobal => "SET_GLOBAL",
+            OpCode::Call => "CALL",
+            OpCode::BuildList => "BUILD_LIST",
+            OpCode::IndexGet => "INDEX_GET",
+            OpCode::IndexSet => "INDEX_SET",
+            OpCode::BuildMap => "BUILD_MAP",
+            OpCode::Stringify => "STRINGIFY",
+        }
+    }
+
+    /// Number of operand bytes that follow the opcode in the chunk.
+    pub fn operand_count(self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::AddConstant
+            | OpCode::LessConstant
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Call
+            | OpCode::BuildList
+            | OpCode::BuildMap => 1,
+            _ => 0,
+        }
+    }
+
+    /// Number of values the instruction pops and pushes, in that order.
+    /// `operands` are the bytes following the opcode.
+    pub fn stack_effect(self, operands: &[u8]) -> (usize, usize) {
+        match self {
+            OpCode::Constant | OpCode::Nil | OpCode::True | OpCode::False | OpCode::GetGlobal => (0, 1),
+            // the callee and its arguments are replaced by the result
+            OpCode::Call => (operands[0] as usize + 1, 1),
+            // the items are replaced by the list
+            OpCode::BuildList => (operands[0] as usize, 1),
+            // the operand counts entries, each a key and a value
+            OpCode::BuildMap => (operands[0] as usize * 2, 1),
+            OpCode::IndexGet => (2, 1),
+            // the list, index and value are replaced by the value
+            OpCode::IndexSet => (3, 1),
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::NotEqual
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual => (2, 1),
+            OpCode::Not
+            | OpCode::Negate
+            | OpCode::AddConstant
+            | OpCode::LessConstant
+            | OpCode::SetGlobal
+            | OpCode::Stringify => (1, 1),
+            OpCode::Return => (1, 0),
+        }
     }
-}
\ No newline at end of file
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        OpCode::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or_else(|| format!("Invalid opcode {}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_from_round_trip() {
+        for (byte, opcode) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*opcode as usize, byte);
+            assert_eq!(OpCode::try_from(byte as u8), Ok(*opcode));
+        }
+        assert!(OpCode::try_from(OpCode::ALL.len() as u8).is_err());
+        assert!(OpCode::try_from(u8::MAX).is_err());
+    }
+}
+
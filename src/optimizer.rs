@@ -0,0 +1,123 @@
+use crate::{chunk::Chunk, debug::disassemble, diagnostic::Span, opcode::OpCode};
+
+/// Rewrites the chunk in place, replacing instruction sequences with cheaper equivalents.
+/// Each rewritten instruction keeps the line of the first instruction it replaces
+/// and the span of the operator, so runtime errors still point at it.
+pub fn optimize(chunk: &mut Chunk) {
+    let instructions = match disassemble(chunk) {
+        Ok(instructions) => instructions,
+        // leave malformed chunks for the verifier to report
+        Err(_) => return,
+    };
+
+    let mut code: Vec<u8> = Vec::with_capacity(chunk.code.len());
+    let mut lines: Vec<usize> = Vec::with_capacity(chunk.lines.len());
+    let mut spans: Vec<Span> = Vec::with_capacity(chunk.spans.len());
+    let span_at = |offset: usize| chunk.spans.get(offset).copied().unwrap_or_default();
+    let mut i = 0;
+    while i < instructions.len() {
+        let instruction = &instructions[i];
+        let fused = instructions.get(i + 1).and_then(|next| fuse(instruction.opcode, next.opcode));
+        if let Some(opcode) = fused {
+            // the fused instruction takes over the operands of the first one
+            code.push(opcode as u8);
+            code.extend_from_slice(&instruction.operands);
+            lines.extend(std::iter::repeat_n(instruction.line, 1 + instruction.operands.len()));
+            // a fused constant only loads the operand of the instruction after it
+            let operator = if instruction.opcode == OpCode::Constant { &instructions[i + 1] } else { instruction };
+            spans.extend(std::iter::repeat_n(span_at(operator.offset), 1 + instruction.operands.len()));
+            i += 2;
+            continue;
+        }
+
+        code.push(instruction.opcode as u8);
+        code.extend_from_slice(&instruction.operands);
+        lines.extend(std::iter::repeat_n(instruction.line, 1 + instruction.operands.len()));
+        spans.extend(std::iter::repeat_n(span_at(instruction.offset), 1 + instruction.operands.len()));
+        i += 1;
+    }
+
+    chunk.code = code;
+    chunk.lines = lines;
+    chunk.spans = spans;
+}
+
+/// Returns the single instruction equivalent to `first` followed by `second`, if there is one.
+fn fuse(first: OpCode, second: OpCode) -> Option<OpCode> {
+    match (first, second) {
+        (OpCode::Equal, OpCode::Not) => Some(OpCode::NotEqual),
+        (OpCode::Less, OpCode::Not) => Some(OpCode::GreaterEqual),
+        (OpCode::Greater, OpCode::Not) => Some(OpCode::LessEqual),
+        (OpCode::Constant, OpCode::Add) => Some(OpCode::AddConstant),
+        (OpCode::Constant, OpCode::Less) => Some(OpCode::LessConstant),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_fuse_comparisons() {
+        let mut chunk = Chunk::default();
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Equal, 2);
+        chunk.write_opcode(OpCode::Not, 3);
+        chunk.write_opcode(OpCode::Nil, 4);
+        chunk.write_opcode(OpCode::Less, 4);
+        chunk.write_opcode(OpCode::Not, 4);
+        chunk.write_opcode(OpCode::Return, 5);
+        optimize(&mut chunk);
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Nil as u8,
+                OpCode::Nil as u8,
+                OpCode::NotEqual as u8,
+                OpCode::Nil as u8,
+                OpCode::GreaterEqual as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(chunk.lines, vec![1, 1, 2, 4, 4, 5]);
+    }
+
+    #[test]
+    fn test_fuse_constant_operands() {
+        let mut chunk = Chunk::default();
+        chunk.write_opcode(OpCode::Nil, 1);
+        let idx = chunk.add_const(crate::value::Value::Number(1.0)) as u8;
+        chunk.write_opcode(OpCode::Constant, 1);
+        chunk.write(idx, 1);
+        chunk.write_opcode(OpCode::Add, 1);
+        chunk.write_opcode(OpCode::Constant, 2);
+        chunk.write(idx, 2);
+        chunk.write_opcode(OpCode::Less, 2);
+        chunk.write_opcode(OpCode::Return, 2);
+        optimize(&mut chunk);
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Nil as u8,
+                OpCode::AddConstant as u8,
+                idx,
+                OpCode::LessConstant as u8,
+                idx,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(chunk.lines, vec![1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_compiler_output_is_optimized() {
+        // negating a string can't be folded, so the comparison survives to the peephole pass
+        let chunk = compile("-\"a\" <= 1").unwrap();
+        assert_eq!(chunk.code.len(), chunk.lines.len());
+        assert_eq!(chunk.code[5], OpCode::LessEqual as u8);
+        assert_eq!(chunk.code[6], OpCode::Return as u8);
+    }
+}
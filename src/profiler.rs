@@ -0,0 +1,122 @@
+use std::{collections::HashMap, fmt::Write, time::{Duration, Instant}};
+
+use crate::opcode::OpCode;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stat {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Stat {
+    fn record(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+/// Collects per opcode, per line and per function execution counts and times.
+///
+/// The VM calls `enter` as each instruction starts; the time until the next
+/// call (or `finish`) is attributed to that instruction.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pub opcodes: HashMap<OpCode, Stat>,
+    pub lines: HashMap<usize, Stat>,
+    pub functions: HashMap<String, Stat>,
+    // (function, line, opcode) for the collapsed stack output
+    stacks: HashMap<(String, usize, OpCode), Stat>,
+    function: String,
+    current: Option<(OpCode, usize, Instant)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the start of execution of a function.
+    pub fn start(&mut self, function: &str) {
+        self.finish();
+        self.function = function.to_string();
+    }
+
+    pub fn enter(&mut self, opcode: OpCode, line: usize) {
+        let now = Instant::now();
+        self.flush(now);
+        self.current = Some((opcode, line, now));
+    }
+
+    /// Attributes the time spent in the last instruction.
+    pub fn finish(&mut self) {
+        self.flush(Instant::now());
+    }
+
+    fn flush(&mut self, now: Instant) {
+        if let Some((opcode, line, start)) = self.current.take() {
+            let time = now - start;
+            self.opcodes.entry(opcode).or_default().record(time);
+            self.lines.entry(line).or_default().record(time);
+            self.functions.entry(self.function.clone()).or_default().record(time);
+            self.stacks.entry((self.function.clone(), line, opcode)).or_default().record(time);
+        }
+    }
+
+    /// A table per category, each sorted by time spent, slowest first.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        write_table(&mut out, "opcode", self.opcodes.iter().map(|(op, stat)| (op.name().to_string(), *stat)));
+        write_table(&mut out, "line", self.lines.iter().map(|(line, stat)| (line.to_string(), *stat)));
+        write_table(&mut out, "function", self.functions.iter().map(|(name, stat)| (name.clone(), *stat)));
+        out
+    }
+
+    /// One `function;line N;OPCODE nanoseconds` line per stack, the format flamegraph tools consume.
+    pub fn collapsed_stacks(&self) -> String {
+        let mut stacks: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|((function, line, opcode), stat)| {
+                format!("{};line {};{} {}", function, line, opcode.name(), stat.time.as_nanos())
+            })
+            .collect();
+        stacks.sort();
+        stacks.iter().map(|stack| stack.clone() + "\n").collect()
+    }
+}
+
+fn write_table(out: &mut String, title: &str, rows: impl Iterator<Item = (String, Stat)>) {
+    let mut rows: Vec<(String, Stat)> = rows.collect();
+    rows.sort_by(|a, b| b.1.time.cmp(&a.1.time).then_with(|| a.0.cmp(&b.0)));
+    writeln!(out, "{:<16} {:>12} {:>14}", title, "count", "time").unwrap();
+    for (name, stat) in rows {
+        writeln!(out, "{:<16} {:>12} {:>14?}", name, stat.count, stat.time).unwrap();
+    }
+    writeln!(out).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let mut profiler = Profiler::new();
+        profiler.start("main");
+        profiler.enter(OpCode::Constant, 1);
+        profiler.enter(OpCode::Constant, 1);
+        profiler.enter(OpCode::Add, 2);
+        profiler.enter(OpCode::Return, 2);
+        profiler.finish();
+
+        assert_eq!(profiler.opcodes[&OpCode::Constant].count, 2);
+        assert_eq!(profiler.opcodes[&OpCode::Add].count, 1);
+        assert_eq!(profiler.lines[&1].count, 2);
+        assert_eq!(profiler.lines[&2].count, 2);
+        assert_eq!(profiler.functions["main"].count, 4);
+
+        let collapsed = profiler.collapsed_stacks();
+        let stacks: Vec<&str> = collapsed.lines().map(|l| l.rsplit_once(' ').unwrap().0).collect();
+        assert_eq!(stacks, vec!["main;line 1;CONSTANT", "main;line 2;ADD", "main;line 2;RETURN"]);
+    }
+}
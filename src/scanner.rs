@@ -1,13 +1,48 @@
+#[derive(Clone)]
 pub struct Scanner<'src> {
     source: &'src str,
     start: usize,
     current: usize,
     line: usize,
+    // 1-based column (counted in characters) of `current`, advanced as
+    // characters are consumed
+    current_column: usize,
+    // line and 1-based column (counted in characters) of `start`
+    start_line: usize,
+    column: usize,
+    keep_comments: bool,
+    // the `${...}` interpolations being scanned, innermost last
+    interpolations: Vec<Interpolation>,
+}
+
+#[derive(Clone)]
+struct Interpolation {
+    // whether the string it belongs to was opened with `"""`
+    triple: bool,
+    // braces opened inside the interpolation and not yet closed
+    braces: usize,
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
-        Self { source, start: 0, current: 0, line: 1 }
+        Self {
+            source,
+            start: 0,
+            current: 0,
+            line: 1,
+            current_column: 1,
+            start_line: 1,
+            column: 1,
+            keep_comments: false,
+            interpolations: Vec::new(),
+        }
+    }
+
+    /// A scanner that also returns comments as `Comment` tokens, for tools that
+    /// must reproduce the source. Together with the token offsets, everything
+    /// except whitespace is covered by a token.
+    pub fn with_comments(source: &'src str) -> Self {
+        Self { keep_comments: true, ..Self::new(source) }
     }
 
     fn is_at_end(&self) -> bool {
@@ -15,41 +50,47 @@ impl<'src> Scanner<'src> {
     }
 
     fn make_token(&self, token_type: TokenType) -> Token<'src> {
-        Token { token_type, lexeme: &self.source[self.start..self.current], line: self.line }
+        let lexeme = &self.source[self.start..self.current];
+        let length = lexeme.len();
+        Token { token_type, lexeme, line: self.start_line, offset: self.start, column: self.column, length }
     }
 
+    /// An error token whose lexeme is the message; the span still covers the offending text.
     fn error_token(&self, message: &'static str) -> Token<'src> {
-        Token { token_type: TokenType::Error, lexeme: message, line: self.line }
+        Token {
+            token_type: TokenType::Error,
+            lexeme: message,
+            line: self.start_line,
+            offset: self.start,
+            column: self.column,
+            length: self.current - self.start,
+        }
     }
 
+    // `start` and `current` are byte offsets, so characters are read from the
+    // remaining slice rather than by index.
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.current_column += 1;
         c
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current).unwrap()
-        }
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current + 1).unwrap()
-        }
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn match_next(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.source.chars().nth(self.current).unwrap() != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
+        self.current_column += 1;
         true
     }
 
@@ -61,9 +102,8 @@ impl<'src> Scanner<'src> {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
-                    break;
+                    self.new_line();
                 }
                 _ => break,
             }
@@ -71,36 +111,170 @@ impl<'src> Scanner<'src> {
     }
 
 
-    fn string(&mut self) -> Token<'src> {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
-            self.advance();
+    // called after consuming a '\n'
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.current_column = 1;
+    }
+
+    /// An error token for the text at `offset`, inside the token being scanned.
+    fn error_token_at(&self, offset: usize, length: usize, message: &'static str) -> Token<'src> {
+        let before = &self.source[self.start..offset];
+        let (line, column) = match before.rfind('\n') {
+            Some(n) => (self.start_line + before.matches('\n').count(), before[n + 1..].chars().count() + 1),
+            None => (self.start_line, self.column + before.chars().count()),
+        };
+        Token { token_type: TokenType::Error, lexeme: message, line, offset, column, length }
+    }
+
+    /// Scans a string whose opening quote was consumed. Raw strings (`r"..."`)
+    /// take backslashes literally, and strings opened with `"""` end at the
+    /// next `"""` so they can contain quotes.
+    fn string(&mut self, raw: bool) -> Token<'src> {
+        let triple = self.source[self.current..].starts_with("\"\"");
+        if triple {
+            self.current += 2;
+            self.current_column += 2;
         }
+        self.string_part(raw, triple)
+    }
 
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.");
+    /// Scans the text of a string up to its closing quotes, or up to a `${`
+    /// which ends the part as an `Interpolation` token. The string continues
+    /// after the `}` that closes the interpolation.
+    fn string_part(&mut self, raw: bool, triple: bool) -> Token<'src> {
+        let body_start = self.current;
+        let mut interpolation = false;
+        loop {
+            if self.is_at_end() {
+                let message = if triple { "Unterminated multi-line string." } else { "Unterminated string." };
+                return self.error_token(message);
+            }
+            match self.advance() {
+                '"' if !triple => break,
+                '"' if self.source[self.current..].starts_with("\"\"") => {
+                    self.current += 2;
+                    self.current_column += 2;
+                    break;
+                }
+                // skip the escaped character so `\"` does not end the string
+                '\\' if !raw && !self.is_at_end() && self.peek() != '\n' => {
+                    self.advance();
+                }
+                '$' if !raw && self.peek() == '{' => {
+                    self.advance();
+                    interpolation = true;
+                    break;
+                }
+                '\n' => self.new_line(),
+                _ => {}
+            }
         }
 
-        self.advance();
+        if !raw {
+            let body_end = self.current - if interpolation { 2 } else if triple { 3 } else { 1 };
+            if let Err((offset, length, message)) = unescape(&self.source[body_start..body_end]) {
+                return self.error_token_at(body_start + offset, length, message);
+            }
+        }
+        if interpolation {
+            self.interpolations.push(Interpolation { triple, braces: 0 });
+            return self.make_token(TokenType::Interpolation);
+        }
         self.make_token(TokenType::String)
     }
 
+    /// Scans a number literal: a decimal with an optional fraction and exponent,
+    /// or an integer in hexadecimal (`0x`), binary (`0b`) or octal (`0o`).
+    /// Underscores may separate digits, as in `1_000`.
     fn number(&mut self) -> Token<'src> {
-        while self.peek().is_digit(10) {
-            self.advance();
+        match self.number_literal() {
+            Ok(()) => self.make_token(TokenType::Number),
+            Err((offset, length, message)) => {
+                // skip the rest of the malformed literal so it is reported once
+                while self.peek().is_alphanumeric()
+                    || self.peek() == '_'
+                    || (self.peek() == '.' && self.peek_next().is_ascii_digit())
+                {
+                    self.advance();
+                }
+                self.error_token_at(offset, length, message)
+            }
         }
+    }
 
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
-            self.advance();
-
-            while self.peek().is_digit(10) {
+    /// Scans the rest of a number literal whose first digit was consumed, or
+    /// returns the offset and length of what is wrong with it and a message.
+    fn number_literal(&mut self) -> Result<(), (usize, usize, &'static str)> {
+        let prefixed = self.source[self.start..].starts_with('0');
+        let radix = match self.peek() {
+            'x' if prefixed => 16,
+            'b' if prefixed => 2,
+            'o' if prefixed => 8,
+            _ => 10,
+        };
+        if radix == 10 {
+            self.digits(10, 1)?;
+            if self.peek() == '.' && self.peek_next().is_ascii_digit() {
                 self.advance();
+                self.digits(10, 0)?;
             }
+            if matches!(self.peek(), 'e' | 'E') {
+                let exponent = self.current;
+                self.advance();
+                if matches!(self.peek(), '+' | '-') {
+                    self.advance();
+                }
+                if self.digits(10, 0)? == 0 {
+                    return Err((exponent, self.current - exponent, "Expect digits in the exponent."));
+                }
+            }
+        } else {
+            self.advance();
+            // a letter or digit out of range right after the prefix is reported below
+            if self.digits(radix, 0)? == 0 && !self.peek().is_alphanumeric() {
+                let message = match radix {
+                    16 => "Expect hexadecimal digits after '0x'.",
+                    8 => "Expect octal digits after '0o'.",
+                    _ => "Expect binary digits after '0b'.",
+                };
+                return Err((self.start, self.current - self.start, message));
+            }
+        }
+
+        // a literal must not run into letters or digits it can't contain, as in `0b102` or `12px`
+        let c = self.peek();
+        if c.is_alphanumeric() || c == '_' {
+            let message = match radix {
+                16 => "Invalid digit in hexadecimal literal.",
+                8 => "Invalid digit in octal literal.",
+                2 => "Invalid digit in binary literal.",
+                _ => "Invalid character in number literal.",
+            };
+            return Err((self.current, c.len_utf8(), message));
         }
+        number_value(&self.source[self.start..self.current])
+            .map(|_| ())
+            .map_err(|message| (self.start, self.current - self.start, message))
+    }
 
-        self.make_token(TokenType::Number)
+    /// Consumes digits in `radix` and the underscores between them, given the
+    /// number of digits just before. Returns how many digits there were.
+    fn digits(&mut self, radix: u32, mut count: usize) -> Result<usize, (usize, usize, &'static str)> {
+        let start = count;
+        loop {
+            let c = self.peek();
+            if c.is_digit(radix) {
+                count += 1;
+            } else if c == '_' && count > 0 && self.peek_next().is_digit(radix) {
+                // a separator between two digits
+            } else if c == '_' {
+                return Err((self.current, 1, "Digit separators must be between digits."));
+            } else {
+                return Ok(count - start);
+            }
+            self.advance();
+        }
     }
 
     fn identifier(&mut self) -> Token<'src> {
@@ -132,13 +306,15 @@ impl<'src> Scanner<'src> {
             "while" => TokenType::While,
             _ => TokenType::Identifier,
         };
-        Token { token_type, lexeme, line: self.line }
+        self.make_token(token_type)
     }
 
     pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.column = self.current_column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -149,10 +325,29 @@ impl<'src> Scanner<'src> {
         match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.braces += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(interpolation) if interpolation.braces == 0 => {
+                    let triple = interpolation.triple;
+                    self.interpolations.pop();
+                    self.string_part(false, triple)
+                }
+                Some(interpolation) => {
+                    interpolation.braces -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
             ',' => self.make_token(TokenType::Comma),
+            ':' => self.make_token(TokenType::Colon),
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
             '+' => self.make_token(TokenType::Plus),
@@ -160,7 +355,11 @@ impl<'src> Scanner<'src> {
                 while self.peek() != '\n' && !self.is_at_end() {
                     self.advance();
                 }
-                return self.scan_token();
+                if self.keep_comments {
+                    self.make_token(TokenType::Comment)
+                } else {
+                    self.scan_token()
+                }
             } else {
                 self.make_token(TokenType::Slash)
             },
@@ -185,14 +384,174 @@ impl<'src> Scanner<'src> {
             } else {
                 self.make_token(TokenType::Greater)
             },
-            '"' => self.string(),
+            '"' => self.string(false),
+            'r' if self.peek() == '"' => {
+                self.advance();
+                self.string(true)
+            }
             '0'..='9' => self.number(),
-            'a'..='z' | 'A'..='Z' | '_' => self.identifier_token(),
+            'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
             _ => self.error_token("Unexpected character."),
         }
     }
 }
 
+/// The value of a number literal token, or why it can't be represented.
+pub fn number_value(lexeme: &str) -> Result<f64, &'static str> {
+    let digits: String = lexeme.chars().filter(|&c| c != '_').collect();
+    let radix = match digits.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _ => 10,
+    };
+    if radix == 10 {
+        let value: f64 = digits.parse().map_err(|_| "Invalid number literal.")?;
+        return if value.is_finite() { Ok(value) } else { Err("Number literal is too large.") };
+    }
+    // integers past 2^53 would silently lose their low bits as floats
+    match u64::from_str_radix(&digits[2..], radix) {
+        Ok(value) if value <= 1 << 53 => Ok(value as f64),
+        _ => Err("Integer literal is too large to be represented exactly."),
+    }
+}
+
+/// The value of a string literal token: the text between the quotes with
+/// escape sequences replaced, and for `"""` strings their layout removed.
+pub fn string_value(lexeme: &str) -> String {
+    let Some(raw) = lexeme.strip_prefix('r') else {
+        let triple = is_triple(lexeme);
+        return string_part_value(lexeme, triple, closing_indent(lexeme, triple));
+    };
+    let triple = is_triple(raw);
+    let body = part_body(raw, triple);
+    if triple {
+        layout(body, true, true, closing_indent(raw, triple))
+    } else {
+        body.to_string()
+    }
+}
+
+/// The value of one part of a string literal, which runs from the opening
+/// quotes or the `}` ending an interpolation to the next `${` or the closing
+/// quotes. `triple` and `indent` describe the whole literal, see
+/// [`is_triple`] and [`closing_indent`].
+pub fn string_part_value(lexeme: &str, triple: bool, indent: Option<&str>) -> String {
+    let body = part_body(lexeme, triple);
+    let body = if triple {
+        layout(body, !lexeme.starts_with('}'), !lexeme.ends_with("${"), indent)
+    } else {
+        body.to_string()
+    };
+    // the scanner only produces string tokens with valid escapes
+    unescape(&body).unwrap_or(body)
+}
+
+/// Whether the first part of a string literal (without any `r` prefix) was
+/// opened with `"""`.
+pub fn is_triple(lexeme: &str) -> bool {
+    lexeme.starts_with("\"\"\"") && (lexeme.len() >= 6 || lexeme.ends_with("${"))
+}
+
+/// For a `"""` string whose last part is `lexeme`, the indentation of the
+/// closing quotes if they are on a line of their own. It is removed from the
+/// start of every line.
+pub fn closing_indent(lexeme: &str, triple: bool) -> Option<&str> {
+    if !triple {
+        return None;
+    }
+    let body = part_body(lexeme, triple);
+    // a line break right after the opening quotes is not part of the string
+    let body = if lexeme.starts_with('}') { body } else { skip_opening_break(body) };
+    let indent = &body[body.rfind('\n')? + 1..];
+    indent.chars().all(|c| c == ' ' || c == '\t').then_some(indent)
+}
+
+fn part_body(lexeme: &str, triple: bool) -> &str {
+    let quotes = if triple { 3 } else { 1 };
+    let start = if lexeme.starts_with('}') { 1 } else { quotes };
+    let end = if lexeme.ends_with("${") { 2 } else { quotes };
+    &lexeme[start..lexeme.len() - end]
+}
+
+fn skip_opening_break(body: &str) -> &str {
+    body.strip_prefix("\r\n").or_else(|| body.strip_prefix('\n')).unwrap_or(body)
+}
+
+/// Removes the layout of a part of a `"""` string: from the `first` part a
+/// line break right after the opening quotes, from the `last` part the line
+/// of the closing quotes, and `indent` from the start of every line.
+fn layout(body: &str, first: bool, last: bool, indent: Option<&str>) -> String {
+    let mut body = if first { skip_opening_break(body) } else { body };
+    let Some(indent) = indent else {
+        return body.to_string();
+    };
+    if last {
+        body = body.strip_suffix(indent).unwrap_or(body);
+        body = body.strip_suffix('\n').unwrap_or(body);
+        body = body.strip_suffix('\r').unwrap_or(body);
+    }
+    body.split('\n')
+        .enumerate()
+        .map(|(index, line)| match line.strip_prefix(indent) {
+            // the first line of a later part continues a line after an interpolation
+            _ if index == 0 && !first => line,
+            Some(rest) => rest,
+            None if line.trim().is_empty() => "",
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replaces the escape sequences in the body of a string literal, or returns
+/// the offset and length in `body` of the first invalid one with a message.
+fn unescape(body: &str) -> Result<String, (usize, usize, &'static str)> {
+    let mut out = String::with_capacity(body.len());
+    let mut i = 0;
+    while let Some(c) = body[i..].chars().next() {
+        if c != '\\' {
+            out.push(c);
+            i += c.len_utf8();
+            continue;
+        }
+        let (escaped, length) = match body[i + 1..].chars().next() {
+            Some('n') => ('\n', 2),
+            Some('t') => ('\t', 2),
+            Some('r') => ('\r', 2),
+            Some('0') => ('\0', 2),
+            Some('\\') => ('\\', 2),
+            Some('"') => ('"', 2),
+            Some('$') => ('$', 2),
+            Some('u') => unicode_escape(&body[i..]).map_err(|(length, message)| (i, length, message))?,
+            Some(other) => return Err((i, 1 + other.len_utf8(), "Invalid escape sequence.")),
+            None => return Err((i, 1, "Invalid escape sequence.")),
+        };
+        out.push(escaped);
+        i += length;
+    }
+    Ok(out)
+}
+
+/// Reads a `\u{...}` escape at the start of `text`, returning the character and
+/// the length of the escape, or the length of the malformed part and a message.
+fn unicode_escape(text: &str) -> Result<(char, usize), (usize, &'static str)> {
+    if !text[2..].starts_with('{') {
+        return Err((2, "Expected '{' after '\\u'."));
+    }
+    let digits = text[3..].find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(text.len() - 3);
+    let closed = text[3 + digits..].starts_with('}');
+    let length = 3 + digits + usize::from(closed);
+    if !closed || digits == 0 || digits > 6 {
+        return Err((length, "Unicode escapes take 1 to 6 hex digits in braces."));
+    }
+    u32::from_str_radix(&text[3..3 + digits], 16)
+        .ok()
+        .and_then(char::from_u32)
+        .map(|c| (c, length))
+        .ok_or((length, "Unicode escape is not a valid character."))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     // Single-character tokens
@@ -200,6 +559,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -219,6 +581,8 @@ pub enum TokenType {
     // Literals
     Identifier,
     String,
+    // the part of a string before a `${`
+    Interpolation,
     Number,
     // Keywords
     And,
@@ -238,22 +602,63 @@ pub enum TokenType {
     Var,
     While,
     // Special
+    Comment,
     Error,
     Eof,
     // Empty token
     Empty,
 }
 
+impl TokenType {
+    /// Whether an operand must come next. A `{` in that position opens a map
+    /// literal rather than a block.
+    pub fn expects_operand(self) -> bool {
+        use TokenType::*;
+        matches!(
+            self,
+            LeftParen
+                | LeftBracket
+                | Comma
+                | Colon
+                | Minus
+                | Plus
+                | Slash
+                | Star
+                | Bang
+                | BangEqual
+                | Equal
+                | EqualEqual
+                | Greater
+                | GreaterEqual
+                | Less
+                | LessEqual
+                | And
+                | Or
+                | Print
+                | Return
+                | Interpolation
+        )
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Token<'src> {
     pub token_type: TokenType,
     pub lexeme: &'src str,
+    /// The line the token starts on.
     pub line: usize,
+    /// Byte offset of the lexeme in the source.
+    pub offset: usize,
+    /// 1-based column where the token starts, counted in characters.
+    pub column: usize,
+    /// Length in bytes of the source the token covers. Differs from the
+    /// lexeme's length for error tokens, whose lexeme is the message.
+    pub length: usize,
 }
 
 impl<'src> Default for Token<'src> {
     fn default() -> Self {
-        Self { token_type: TokenType::Empty, lexeme: "", line: 0 }
+        Self { token_type: TokenType::Empty, lexeme: "", line: 0, offset: 0, column: 0, length: 0 }
     }
 }
 
@@ -280,4 +685,177 @@ mod tests {
         assert_token!(tokens, 1, TokenType::Star);
         assert_token!(tokens, 2, TokenType::Number);
     }
+
+    #[test]
+    fn test_whitespace_across_lines() {
+        let src = "1\n\n  + 2";
+        let mut scanner = Scanner::new(src);
+        assert_eq!(scanner.scan_token().line, 1);
+        let plus = scanner.scan_token();
+        assert_eq!(plus.token_type, TokenType::Plus);
+        assert_eq!(plus.line, 3);
+        assert_eq!(scanner.scan_token().token_type, TokenType::Number);
+        assert_eq!(scanner.scan_token().token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_offsets_with_multibyte_characters() {
+        let src = "\"héllo\" + ünïcode";
+        let mut scanner = Scanner::new(src);
+        let string = scanner.scan_token();
+        assert_eq!(string.lexeme, "\"héllo\"");
+        assert_eq!(scanner.scan_token().offset, 9);
+        let identifier = scanner.scan_token();
+        assert_eq!(identifier.token_type, TokenType::Error);
+        assert_eq!(identifier.offset, 11);
+    }
+
+    #[test]
+    fn test_comments() {
+        let src = "1 // one\n// two\n+ 2";
+        let mut scanner = Scanner::new(src);
+        let types: Vec<TokenType> = (0..4).map(|_| scanner.scan_token().token_type).collect();
+        assert_eq!(types, vec![TokenType::Number, TokenType::Plus, TokenType::Number, TokenType::Eof]);
+
+        let mut scanner = Scanner::with_comments(src);
+        let one = scanner.scan_token();
+        let comment = scanner.scan_token();
+        assert_eq!((comment.token_type, comment.lexeme, comment.line), (TokenType::Comment, "// one", 1));
+        assert_eq!(&src[comment.offset..one.offset + 8], "// one");
+        assert_eq!(scanner.scan_token().lexeme, "// two");
+        assert_eq!(scanner.scan_token().token_type, TokenType::Plus);
+    }
+
+    #[test]
+    fn test_columns_and_spans() {
+        let src = "a +
+  \"b\nc\" + ü\n  \"open";
+        let mut scanner = Scanner::new(src);
+        let spans: Vec<(TokenType, usize, usize, usize)> = (0..6)
+            .map(|_| scanner.scan_token())
+            .map(|token| (token.token_type, token.line, token.column, token.length))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (TokenType::Identifier, 1, 1, 1),
+                (TokenType::Plus, 1, 3, 1),
+                // a string spanning lines reports where it starts
+                (TokenType::String, 2, 3, 5),
+                (TokenType::Plus, 3, 4, 1),
+                (TokenType::Error, 3, 6, 2),
+                (TokenType::Error, 4, 3, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_columns_after_multi_character_tokens() {
+        let src = "\"\"\"é\"\"\" == \"${ü}\" !x";
+        let mut scanner = Scanner::new(src);
+        let columns: Vec<usize> = (0..7).map(|_| scanner.scan_token().column).collect();
+        assert_eq!(columns, vec![1, 9, 12, 15, 16, 19, 20]);
+    }
+
+    #[test]
+    fn test_string_literals() {
+        let value = |src: &str| {
+            let token = Scanner::new(src).scan_token();
+            assert_eq!((token.token_type, token.lexeme), (TokenType::String, src));
+            string_value(token.lexeme)
+        };
+        assert_eq!(value(r#""a\"b\tc\\ \u{e9}\u{1F600}""#), "a\"b\tc\\ é😀");
+        assert_eq!(value(r#"r"C:\dir\n""#), "C:\\dir\\n");
+        assert_eq!(value("\"\"\"say \"hi\"!\"\"\""), "say \"hi\"!");
+        assert_eq!(value("\"\"\"\n    SELECT *\n\n      FROM t\\n\n    \"\"\""), "SELECT *\n\n  FROM t\n");
+        assert_eq!(value("r\"\"\"\n  \\d+\n  \"\"\""), "\\d+");
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let src = "\"a${ {\"b\": \"}\"}[\"${c}\"] }d\" }";
+        let mut scanner = Scanner::new(src);
+        let tokens: Vec<(TokenType, &str)> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.token_type != TokenType::Eof).then_some((token.token_type, token.lexeme))
+        })
+        .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Interpolation, "\"a${"),
+                (TokenType::LeftBrace, "{"),
+                (TokenType::String, "\"b\""),
+                (TokenType::Colon, ":"),
+                // braces inside nested strings are text
+                (TokenType::String, "\"}\""),
+                (TokenType::RightBrace, "}"),
+                (TokenType::LeftBracket, "["),
+                (TokenType::Interpolation, "\"${"),
+                (TokenType::Identifier, "c"),
+                (TokenType::String, "}\""),
+                (TokenType::RightBracket, "]"),
+                (TokenType::String, "}d\""),
+                // outside any string, `}` is a brace again
+                (TokenType::RightBrace, "}"),
+            ]
+        );
+        assert_eq!(string_part_value("}d\\$\"", false, None), "d$");
+
+        // the indentation of the closing quotes comes from the last part
+        let last = "}\n    x\n  \"\"\"";
+        assert_eq!(closing_indent(last, true), Some("  "));
+        assert_eq!(string_part_value("\"\"\"\n    a ${", true, Some("  ")), "  a ");
+        assert_eq!(string_part_value(last, true, Some("  ")), "\n  x");
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let value = |src: &str| {
+            let token = Scanner::new(src).scan_token();
+            assert_eq!((token.token_type, token.lexeme), (TokenType::Number, src));
+            number_value(token.lexeme).unwrap()
+        };
+        assert_eq!(value("0xFF"), 255.0);
+        assert_eq!(value("0xdead_beef"), 3735928559.0);
+        assert_eq!(value("0b1010"), 10.0);
+        assert_eq!(value("0o17"), 15.0);
+        assert_eq!(value("1_000_000"), 1e6);
+        assert_eq!(value("1.5e-3"), 0.0015);
+        assert_eq!(value("2E+1_0"), 2e10);
+        assert_eq!(value("0x20000000000000"), 9007199254740992.0);
+
+        fn error(src: &str) -> (&str, &str) {
+            let mut scanner = Scanner::new(src);
+            let token = scanner.scan_token();
+            assert_eq!(scanner.scan_token().token_type, TokenType::Eof, "the whole literal is skipped");
+            (token.lexeme, &src[token.offset..token.offset + token.length])
+        }
+        assert_eq!(error("0b1021"), ("Invalid digit in binary literal.", "2"));
+        assert_eq!(error("0o8"), ("Invalid digit in octal literal.", "8"));
+        assert_eq!(error("10px"), ("Invalid character in number literal.", "p"));
+        assert_eq!(error("0x"), ("Expect hexadecimal digits after '0x'.", "0x"));
+        assert_eq!(error("1__000"), ("Digit separators must be between digits.", "_"));
+        assert_eq!(error("1_.5"), ("Digit separators must be between digits.", "_"));
+        assert_eq!(error("1e-x"), ("Expect digits in the exponent.", "e-"));
+        assert_eq!(error("1e999"), ("Number literal is too large.", "1e999"));
+        let inexact = "0x20000000000001";
+        assert_eq!(error(inexact), ("Integer literal is too large to be represented exactly.", inexact));
+    }
+
+    #[test]
+    fn test_string_errors() {
+        fn error(src: &str) -> (&str, usize, usize, &str) {
+            let mut scanner = Scanner::new(src);
+            let token = scanner.scan_token();
+            assert_eq!(scanner.scan_token().token_type, TokenType::Eof, "the whole string is skipped");
+            (token.lexeme, token.line, token.column, &src[token.offset..token.offset + token.length])
+        }
+        assert_eq!(error(r#""ab\qc""#), ("Invalid escape sequence.", 1, 4, r"\q"));
+        let invalid = ("Unicode escape is not a valid character.", 2, 4, r"\u{110000}");
+        assert_eq!(error("\"\"\"\nhé \\u{110000}\"\"\""), invalid);
+        assert_eq!(error(r#""\u{12""#), ("Unicode escapes take 1 to 6 hex digits in braces.", 1, 2, r"\u{12"));
+        assert_eq!(error(r#""\u41""#), ("Expected '{' after '\\u'.", 1, 2, r"\u"));
+        assert_eq!(error("\"\"\"a\"\""), ("Unterminated multi-line string.", 1, 1, "\"\"\"a\"\""));
+    }
 }
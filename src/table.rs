@@ -0,0 +1,256 @@
+use crate::value::Value;
+
+// markers in `slots` for a slot that was never used and one whose entry was removed
+const EMPTY: u32 = u32::MAX;
+const TOMBSTONE: u32 = u32::MAX - 1;
+
+/// The hash table behind Lox maps.
+///
+/// Entries are kept in insertion order in `entries`, and `slots` is an open
+/// addressing index into them probed linearly, so iteration follows insertion
+/// order no matter how the keys hash. Overwriting a key keeps its position;
+/// removing it and inserting it again moves it to the end.
+///
+/// Keys may be strings, numbers, booleans or nil. Numbers are compared by
+/// value except that `-0.0` is the same key as `0.0` and every NaN is the same
+/// key, so that each key can be looked up again after it was inserted.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    entries: Vec<Option<(Value, Value)>>,
+    slots: Vec<u32>,
+    len: usize,
+    // slots holding an index or a tombstone, which both lengthen probes
+    used: usize,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Option<&Value>, String> {
+        check_key(key)?;
+        Ok(self.find(key).map(|(_, index)| &self.entries[index].as_ref().unwrap().1))
+    }
+
+    /// Sets `key` to `value`, returning the value it replaced.
+    pub fn insert(&mut self, key: Value, value: Value) -> Result<Option<Value>, String> {
+        check_key(&key)?;
+        if let Some((_, index)) = self.find(&key) {
+            let entry = self.entries[index].as_mut().unwrap();
+            return Ok(Some(std::mem::replace(&mut entry.1, value)));
+        }
+        // grow before the table is three quarters full, counting tombstones, and
+        // compact once removed entries outnumber live ones: a new key may reuse a
+        // tombstone, so without this churn would grow `entries` without bound
+        if (self.used + 1) * 4 > self.slots.len() * 3 || self.entries.len() > self.len * 2 {
+            self.resize();
+        }
+        // entry indices share the slot values with the EMPTY and TOMBSTONE markers
+        if self.entries.len() >= TOMBSTONE as usize {
+            return Err("Too many entries in one map".to_string());
+        }
+        let mut slot = self.hash_slot(&key);
+        loop {
+            match self.slots[slot] {
+                EMPTY => {
+                    self.used += 1;
+                    break;
+                }
+                TOMBSTONE => break,
+                _ => slot = (slot + 1) % self.slots.len(),
+            }
+        }
+        self.slots[slot] = self.entries.len() as u32;
+        self.entries.push(Some((key, value)));
+        self.len += 1;
+        Ok(None)
+    }
+
+    /// Removes `key`, returning its value if it was present.
+    pub fn remove(&mut self, key: &Value) -> Result<Option<Value>, String> {
+        check_key(key)?;
+        let Some((slot, index)) = self.find(key) else {
+            return Ok(None);
+        };
+        self.slots[slot] = TOMBSTONE;
+        self.len -= 1;
+        Ok(self.entries[index].take().map(|(_, value)| value))
+    }
+
+    /// The entries in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.entries.iter().flatten().map(|(key, value)| (key, value))
+    }
+
+    /// The slot and entry index holding `key`.
+    fn find(&self, key: &Value) -> Option<(usize, usize)> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mut slot = self.hash_slot(key);
+        loop {
+            match self.slots[slot] {
+                EMPTY => return None,
+                TOMBSTONE => {}
+                index => {
+                    let (existing, _) = self.entries[index as usize].as_ref().unwrap();
+                    if same_key(existing, key) {
+                        return Some((slot, index as usize));
+                    }
+                }
+            }
+            slot = (slot + 1) % self.slots.len();
+        }
+    }
+
+    fn hash_slot(&self, key: &Value) -> usize {
+        hash(key) as usize % self.slots.len()
+    }
+
+    /// Rebuilds the index with room to grow, dropping removed entries and tombstones.
+    fn resize(&mut self) {
+        let capacity = (self.len * 2).max(8).next_power_of_two();
+        let entries: Vec<Option<(Value, Value)>> =
+            std::mem::take(&mut self.entries).into_iter().flatten().map(Some).collect();
+        self.slots = vec![EMPTY; capacity];
+        for (index, entry) in entries.iter().enumerate() {
+            let mut slot = self.hash_slot(&entry.as_ref().unwrap().0);
+            while self.slots[slot] != EMPTY {
+                slot = (slot + 1) % capacity;
+            }
+            self.slots[slot] = index as u32;
+        }
+        self.used = entries.len();
+        self.entries = entries;
+    }
+}
+
+/// Maps are equal when they hold equal values under the same keys, in any order.
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.equals(other, &mut Vec::new())
+    }
+}
+
+impl Table {
+    /// Compares like `==`, sharing `seen` with `Value::equals` so maps that
+    /// contain themselves compare without recursing forever.
+    pub(crate) fn equals(&self, other: &Table, seen: &mut Vec<(*const (), *const ())>) -> bool {
+        self.len == other.len
+            && self.iter().all(|(key, value)| match other.get(key) {
+                Ok(Some(other)) => value.equals(other, seen),
+                _ => false,
+            })
+    }
+}
+
+fn check_key(key: &Value) -> Result<(), String> {
+    match key {
+        Value::String(_) | Value::Number(_) | Value::Bool(_) | Value::Nil => Ok(()),
+        _ => Err(format!("Cannot use {} as a map key", key)),
+    }
+}
+
+/// The bits a number is hashed and compared by as a key.
+fn number_bits(n: f64) -> u64 {
+    if n == 0.0 {
+        0
+    } else if n.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        n.to_bits()
+    }
+}
+
+fn same_key(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(n), Value::Number(m)) => number_bits(*n) == number_bits(*m),
+        _ => a == b,
+    }
+}
+
+/// 64 bit FNV-1a over a tag for the type followed by the key's bytes.
+fn hash(key: &Value) -> u64 {
+    let fnv = |hash: u64, bytes: &[u8]| {
+        bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+    };
+    let basis: u64 = 0xcbf29ce484222325;
+    match key {
+        Value::Nil => fnv(basis, &[0]),
+        Value::Bool(b) => fnv(fnv(basis, &[1]), &[*b as u8]),
+        Value::Number(n) => fnv(fnv(basis, &[2]), &number_bits(*n).to_le_bytes()),
+        Value::String(s) => fnv(fnv(basis, &[3]), s.as_bytes()),
+        _ => unreachable!("keys are checked before hashing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_churn_reuses_space() {
+        let mut table = Table::new();
+        table.insert(Value::from("kept"), Value::Nil).unwrap();
+        for i in 0..10_000 {
+            table.insert(Value::Number(i as f64), Value::Nil).unwrap();
+            table.remove(&Value::Number(i as f64)).unwrap();
+        }
+        assert_eq!(table.len(), 1);
+        assert!(table.entries.len() <= 3, "{} entries kept", table.entries.len());
+        assert!(table.slots.len() <= 8, "{} slots kept", table.slots.len());
+        assert_eq!(table.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_insertion_order_and_removal() {
+        let mut table = Table::new();
+        for i in 0..100 {
+            table.insert(Value::Number(i as f64), Value::Number(i as f64 * 2.0)).unwrap();
+        }
+        for i in (0..100).step_by(2) {
+            assert_eq!(table.remove(&Value::Number(i as f64)), Ok(Some(Value::Number(i as f64 * 2.0))));
+        }
+        // overwriting keeps the position, removing and inserting again moves to the end
+        table.insert(Value::Number(1.0), Value::Nil).unwrap();
+        table.remove(&Value::Number(3.0)).unwrap();
+        table.insert(Value::Number(3.0), Value::Nil).unwrap();
+        assert_eq!(table.len(), 50);
+        let keys: Vec<String> = table.iter().take(3).map(|(key, _)| key.to_string()).collect();
+        assert_eq!(keys, vec!["1", "5", "7"]);
+        assert_eq!(table.iter().last().unwrap().0, &Value::Number(3.0));
+        assert_eq!(table.get(&Value::Number(1.0)), Ok(Some(&Value::Nil)));
+        assert_eq!(table.get(&Value::Number(2.0)), Ok(None));
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut table = Table::new();
+        table.insert(Value::Number(0.0), Value::from("zero")).unwrap();
+        table.insert(Value::Number(f64::NAN), Value::from("nan")).unwrap();
+        table.insert(Value::from("0"), Value::from("string")).unwrap();
+        table.insert(Value::Nil, Value::from("nil")).unwrap();
+        table.insert(Value::Bool(false), Value::from("false")).unwrap();
+        assert_eq!(table.get(&Value::Number(-0.0)), Ok(Some(&Value::from("zero"))));
+        assert_eq!(table.get(&Value::Number(-f64::NAN)), Ok(Some(&Value::from("nan"))));
+        assert_eq!(table.get(&Value::from("0")), Ok(Some(&Value::from("string"))));
+        assert_eq!(table.get(&Value::Nil), Ok(Some(&Value::from("nil"))));
+        assert_eq!(table.len(), 5);
+        assert!(table.insert(Value::list(vec![]), Value::Nil).is_err());
+
+        let mut other = Table::new();
+        for (key, value) in table.iter().collect::<Vec<_>>().into_iter().rev() {
+            other.insert(key.clone(), value.clone()).unwrap();
+        }
+        assert_eq!(table, other);
+    }
+}
@@ -1,25 +1,200 @@
-use std::fmt::Display;
+use std::{cell::RefCell, fmt::Display, rc::Rc};
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::table::Table;
+
+#[derive(Debug, Clone)]
 pub enum Value {
     String(String),
     Number(f64),
     Bool(bool),
     Nil,
+    /// Lists are shared: copies of the value see each other's changes.
+    List(Rc<RefCell<Vec<Value>>>),
+    /// Maps are shared like lists.
+    Map(Rc<RefCell<Table>>),
+    Native(Rc<Native>),
+}
+
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// A function implemented in Rust and callable from Lox.
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+
+impl Native {
+    pub fn new(name: &str, arity: usize, function: impl Fn(&[Value]) -> Result<Value, String> + 'static) -> Self {
+        Self { name: name.to_string(), arity, function: Box::new(function) }
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value, String> {
+        if args.len() != self.arity {
+            return Err(format!("Expected {} arguments but got {}", self.arity, args.len()));
+        }
+        (self.function)(args)
+    }
+}
+
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+// natives are only equal to themselves
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.equals(other, &mut Vec::new())
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::String(s) => write!(f, "{}", s),
-            Self::Number(n) => write!(f, "{}", n),
+            Self::Number(n) => fmt_number(f, *n),
             Self::Bool(b) => write!(f, "{}", b),
             Self::Nil => write!(f, "nil"),
+            Self::List(_) | Self::Map(_) => self.fmt_nested(f, &mut Vec::new()),
+            Self::Native(native) => write!(f, "<native fn {}>", native.name),
         }
     }
 }
 
+/// Writes a number in the shortest form that reads back as the same number:
+/// integers without a fraction, and very large or small magnitudes in
+/// scientific notation, e.g. `1e21` and `1.5e-7`.
+fn fmt_number(f: &mut std::fmt::Formatter<'_>, n: f64) -> std::fmt::Result {
+    let magnitude = n.abs();
+    if magnitude != 0.0 && n.is_finite() && !(1e-7..1e21).contains(&magnitude) {
+        write!(f, "{:e}", n)
+    } else {
+        write!(f, "{}", n)
+    }
+}
+
 impl Value {
+    /// The value as it is shown inside a collection, with strings quoted.
+    pub fn repr(&self) -> String {
+        struct Repr<'a>(&'a Value);
+        impl Display for Repr<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt_nested(f, &mut Vec::new())
+            }
+        }
+        Repr(self).to_string()
+    }
+
+    /// The value as `to_string` shows it, or `None` once the text would exceed
+    /// `limit` bytes. Formatting stops there, so an oversized result is never
+    /// allocated.
+    pub fn to_string_within(&self, limit: usize) -> Option<String> {
+        struct Bounded {
+            text: String,
+            limit: usize,
+        }
+        impl std::fmt::Write for Bounded {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                if self.text.len() + s.len() > self.limit {
+                    return Err(std::fmt::Error);
+                }
+                self.text.push_str(s);
+                Ok(())
+            }
+        }
+        use std::fmt::Write;
+        let mut out = Bounded { text: String::new(), limit };
+        write!(out, "{}", self).ok()?;
+        Some(out.text)
+    }
+
+    /// Formats a value inside a collection, where strings are quoted. `open` holds
+    /// the collections being printed so one that contains itself prints as `[...]` or `{...}`.
+    fn fmt_nested(&self, f: &mut std::fmt::Formatter<'_>, open: &mut Vec<*const ()>) -> std::fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "{:?}", s),
+            Self::List(list) if open.contains(&Rc::as_ptr(list).cast()) => write!(f, "[...]"),
+            Self::Map(map) if open.contains(&Rc::as_ptr(map).cast()) => write!(f, "{{...}}"),
+            Self::List(list) => {
+                open.push(Rc::as_ptr(list).cast());
+                write!(f, "[")?;
+                for (i, item) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.fmt_nested(f, open)?;
+                }
+                open.pop();
+                write!(f, "]")
+            }
+            Self::Map(map) => {
+                open.push(Rc::as_ptr(map).cast());
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    key.fmt_nested(f, open)?;
+                    write!(f, ": ")?;
+                    value.fmt_nested(f, open)?;
+                }
+                open.pop();
+                write!(f, "}}")
+            }
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl Value {
+    /// Compares by contents, like `==`. `seen` holds the pairs of collections
+    /// already being compared; meeting one again adds nothing new, so it counts
+    /// as equal and collections that contain themselves compare without
+    /// recursing forever.
+    pub(crate) fn equals(&self, other: &Value, seen: &mut Vec<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::List(a), Value::List(b)) => {
+                let pair = (Rc::as_ptr(a).cast(), Rc::as_ptr(b).cast());
+                if Rc::ptr_eq(a, b) || seen.contains(&pair) {
+                    return true;
+                }
+                seen.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.equals(b, seen))
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                let pair = (Rc::as_ptr(a).cast(), Rc::as_ptr(b).cast());
+                if Rc::ptr_eq(a, b) || seen.contains(&pair) {
+                    return true;
+                }
+                seen.push(pair);
+                a.borrow().equals(&b.borrow(), seen)
+            }
+            (Value::Native(a), Value::Native(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn map(table: Table) -> Value {
+        Value::Map(Rc::new(RefCell::new(table)))
+    }
+
     pub fn is_truthy(&self) -> bool {
         match self {
             Value::Bool(b) => *b,
@@ -28,6 +203,47 @@ impl Value {
         }
     }
 
+    /// Bytes this value owns on the heap: the text of its strings and the slots
+    /// of its lists and maps, including those of the values they hold. A
+    /// collection reached more than once, for example one that contains
+    /// itself, is counted once.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size_seen(&mut Vec::new())
+    }
+
+    fn heap_size_seen(&self, seen: &mut Vec<*const ()>) -> usize {
+        match self {
+            Value::String(s) => s.len(),
+            Value::List(list) if seen.contains(&Rc::as_ptr(list).cast()) => 0,
+            Value::Map(map) if seen.contains(&Rc::as_ptr(map).cast()) => 0,
+            Value::List(list) => {
+                seen.push(Rc::as_ptr(list).cast());
+                let list = list.borrow();
+                Value::slots_size(list.len()) + list.iter().map(|item| item.heap_size_seen(seen)).sum::<usize>()
+            }
+            Value::Map(map) => {
+                seen.push(Rc::as_ptr(map).cast());
+                let map = map.borrow();
+                let entries: usize = map.iter().map(|(key, value)| key.heap_size_seen(seen) + value.heap_size_seen(seen)).sum();
+                Value::slots_size(map.len() * 2) + entries
+            }
+            _ => 0,
+        }
+    }
+
+    /// Bytes a collection spends on `count` values, not counting what they own.
+    pub fn slots_size(count: usize) -> usize {
+        count * std::mem::size_of::<Value>()
+    }
+
+    /// Bytes `add` would allocate for the result.
+    pub fn concat_len(&self, other: &Value) -> usize {
+        match (self, other) {
+            (Value::String(s), Value::String(t)) => s.len() + t.len(),
+            _ => 0,
+        }
+    }
+
     pub fn not(&self) -> Result<Value, String> {
         match self {
             Value::Bool(b) => Ok(Value::Bool(!b)),
@@ -45,7 +261,7 @@ impl Value {
     pub fn add(&self, other: &Value) -> Result<Value, String> {
         match (self, other) {
             (Value::Number(n), Value::Number(m)) => Ok(Value::Number(n + m)),
-            (Value::String(s), Value::String(t)) => Ok(Value::String(s.clone() + &t)),
+            (Value::String(s), Value::String(t)) => Ok(Value::String(s.clone() + t)),
             _ => Err(format!("Cannot add {} and {}", self, other)),
         }
     }
@@ -78,6 +294,40 @@ impl Value {
         }
     }
 
+    /// `self[index]` for lists, strings and maps. Negative indices count from the end
+    /// of lists and strings, and a key missing from a map is an error.
+    pub fn index(&self, index: &Value) -> Result<Value, String> {
+        match self {
+            Value::List(list) => {
+                let list = list.borrow();
+                Ok(list[resolve_index(index, list.len())?].clone())
+            }
+            Value::String(s) => {
+                let i = resolve_index(index, s.chars().count())?;
+                Ok(Value::String(s.chars().nth(i).unwrap().to_string()))
+            }
+            Value::Map(map) => match map.borrow().get(index)? {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("Key {} not found", index.repr())),
+            },
+            _ => Err(format!("Cannot index {}", self)),
+        }
+    }
+
+    /// `self[index] = value` for lists and maps, which gain the key if it is missing.
+    pub fn set_index(&self, index: &Value, value: Value) -> Result<(), String> {
+        match self {
+            Value::List(list) => {
+                let mut list = list.borrow_mut();
+                let i = resolve_index(index, list.len())?;
+                list[i] = value;
+                Ok(())
+            }
+            Value::Map(map) => map.borrow_mut().insert(index.clone(), value).map(|_| ()),
+            _ => Err(format!("Cannot assign to an index of {}", self)),
+        }
+    }
+
     pub fn equal(&self, other: &Value) -> Value {
         Value::Bool(self == other)
     }
@@ -99,4 +349,212 @@ impl Value {
             _ => Value::Bool(false),
         }
     }
+
+    // defined as the negation of less and greater so non-numbers and NaN
+    // compare exactly as the unfused `Less, Not` and `Greater, Not` pairs do
+    pub fn greater_equal(&self, other: &Value) -> Value {
+        Value::Bool(!self.less(other).is_truthy())
+    }
+
+    pub fn less_equal(&self, other: &Value) -> Value {
+        Value::Bool(!self.greater(other).is_truthy())
+    }
+}
+
+/// Converts a Lox index into a position in a sequence of `len` items.
+pub fn resolve_index(index: &Value, len: usize) -> Result<usize, String> {
+    let n = match index {
+        Value::Number(n) if n.fract() == 0.0 => *n,
+        _ => return Err(format!("Index must be an integer, not {}", index)),
+    };
+    let position = if n < 0.0 { n + len as f64 } else { n };
+    if position < 0.0 || position >= len as f64 {
+        return Err(format!("Index {} out of bounds for length {}", n, len));
+    }
+    Ok(position as usize)
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(n) => Ok(n),
+            _ => Err(format!("Expected a number but got {}", value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(format!("Expected a boolean but got {}", value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(s) => Ok(s),
+            _ => Err(format!("Expected a string but got {}", value)),
+        }
+    }
+}
+
+// `nil` converts to `None`, anything else must convert to the inner type
+macro_rules! option_try_from {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<Value> for Option<$t> {
+                type Error = String;
+
+                fn try_from(value: Value) -> Result<Self, Self::Error> {
+                    match value {
+                        Value::Nil => Ok(None),
+                        value => <$t>::try_from(value).map(Some),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+option_try_from!(f64, bool, String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rust_conversions() {
+        assert_eq!(Value::from(1.5), Value::Number(1.5));
+        assert_eq!(Value::from("a"), Value::String("a".to_string()));
+        assert_eq!(Value::from(None::<bool>), Value::Nil);
+        assert_eq!(Value::from(Some(true)), Value::Bool(true));
+
+        assert_eq!(f64::try_from(Value::Number(2.0)), Ok(2.0));
+        assert_eq!(String::try_from(Value::from("x")), Ok("x".to_string()));
+        assert!(bool::try_from(Value::Nil).is_err());
+        assert_eq!(Option::<f64>::try_from(Value::Nil), Ok(None));
+        assert_eq!(Option::<f64>::try_from(Value::Number(3.0)), Ok(Some(3.0)));
+        assert!(Option::<f64>::try_from(Value::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn test_number_display_round_trips() {
+        let numbers = [0.0, -0.0, 100.0, 0.1 + 0.2, 1e-7, 1.5e-8, 1e20, 1e21, -2.5e300, 5e-324, f64::MAX];
+        let shown: Vec<String> = numbers.iter().map(|&n| Value::Number(n).to_string()).collect();
+        assert_eq!(
+            shown,
+            vec![
+                "0",
+                "-0",
+                "100",
+                "0.30000000000000004",
+                "0.0000001",
+                "1.5e-8",
+                "100000000000000000000",
+                "1e21",
+                "-2.5e300",
+                "5e-324",
+                "1.7976931348623157e308"
+            ]
+        );
+        for (n, text) in numbers.iter().zip(&shown) {
+            assert_eq!(text.parse::<f64>().unwrap().to_bits(), n.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_lists() {
+        let list = Value::list(vec![Value::Number(1.0), Value::from("a"), Value::list(vec![])]);
+        assert_eq!(list.to_string(), "[1, \"a\", []]");
+        assert_eq!(list.index(&Value::Number(-2.0)), Ok(Value::from("a")));
+        assert_eq!(list.index(&Value::Number(3.0)).unwrap_err(), "Index 3 out of bounds for length 3");
+        assert_eq!(list.index(&Value::Number(-4.0)).unwrap_err(), "Index -4 out of bounds for length 3");
+        assert_eq!(list.index(&Value::Number(0.5)).unwrap_err(), "Index must be an integer, not 0.5");
+        assert_eq!(Value::from("héllo").index(&Value::Number(1.0)), Ok(Value::from("é")));
+
+        // copies share the list, and a list holding itself still prints
+        let alias = list.clone();
+        alias.set_index(&Value::Number(2.0), list.clone()).unwrap();
+        assert_eq!(list.to_string(), "[1, \"a\", [...]]");
+        assert!(Value::from("a").set_index(&Value::Number(0.0), Value::Nil).is_err());
+    }
+
+    #[test]
+    fn test_cyclic_list_equality() {
+        let cycle = |item: f64| {
+            let list = Value::list(vec![Value::Number(item)]);
+            list.set_index(&Value::Number(0.0), list.clone()).unwrap();
+            if let Value::List(items) = &list {
+                items.borrow_mut().push(Value::Number(item));
+            }
+            list
+        };
+        let (a, b) = (cycle(1.0), cycle(1.0));
+        assert_eq!(a, a);
+        assert_eq!(a, b);
+        assert_ne!(a, cycle(2.0));
+        assert_eq!(Value::list(vec![a.clone(), b.clone()]), Value::list(vec![b, a]));
+    }
+
+    #[test]
+    fn test_cyclic_map_equality() {
+        let cycle = |item: f64| {
+            let map = Value::map(Table::new());
+            map.set_index(&Value::from("self"), map.clone()).unwrap();
+            map.set_index(&Value::from("item"), Value::Number(item)).unwrap();
+            map
+        };
+        let (a, b) = (cycle(1.0), cycle(1.0));
+        assert_eq!(a, a);
+        assert_eq!(a, b);
+        assert_ne!(a, cycle(2.0));
+        // a list and a map that hold each other
+        let list = Value::list(vec![a.clone()]);
+        a.set_index(&Value::from("list"), list.clone()).unwrap();
+        b.set_index(&Value::from("list"), Value::list(vec![b.clone()])).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(list, Value::list(vec![b]));
+    }
 }
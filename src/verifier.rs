@@ -0,0 +1,95 @@
+use crate::{chunk::Chunk, debug::disassemble, opcode::OpCode, vm::STACK_MAX};
+
+/// Checks that a chunk is safe to hand to the VM.
+///
+/// Every instruction must decode, jumps must land on instruction boundaries,
+/// the stack may never underflow or exceed `STACK_MAX`, and execution must end
+/// in a `Return` with exactly one value on the stack.
+pub fn verify(chunk: &Chunk) -> Result<(), String> {
+    if chunk.lines.len() != chunk.code.len() {
+        return Err(format!(
+            "Line table has {} entries for {} bytes of code",
+            chunk.lines.len(),
+            chunk.code.len()
+        ));
+    }
+
+    let instructions = disassemble(chunk)?;
+
+    for instruction in &instructions {
+        if let Some(target) = instruction.jump_target {
+            if !instructions.iter().any(|i| i.offset == target) {
+                return Err(format!(
+                    "Jump at offset {} targets {}, which is not an instruction boundary",
+                    instruction.offset, target
+                ));
+            }
+        }
+    }
+
+    let mut depth: usize = 0;
+    for instruction in &instructions {
+        let (pops, pushes) = instruction.opcode.stack_effect(&instruction.operands);
+        if depth < pops {
+            return Err(format!(
+                "Stack underflow: {} at offset {} pops {} value(s) but only {} available",
+                instruction.opcode.name(),
+                instruction.offset,
+                pops,
+                depth
+            ));
+        }
+        depth = depth - pops + pushes;
+        if depth > STACK_MAX {
+            return Err(format!("Stack overflow at offset {}", instruction.offset));
+        }
+        if instruction.opcode == OpCode::Return {
+            if depth != 0 {
+                return Err(format!(
+                    "Unbalanced stack: {} value(s) left behind at offset {}",
+                    depth, instruction.offset
+                ));
+            }
+            if instruction.next_offset() != chunk.code.len() {
+                return Err(format!("Unreachable code after offset {}", instruction.offset));
+            }
+            return Ok(());
+        }
+    }
+
+    Err("Chunk does not end with a return".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_verify_compiled_chunk() {
+        let chunk = compile("!(1 + 2 * 3 == 7) != false").unwrap();
+        assert_eq!(verify(&chunk), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_rejects_invalid_chunks() {
+        let mut chunk = Chunk::default();
+        chunk.write_opcode(OpCode::Add, 1);
+        chunk.write_opcode(OpCode::Return, 1);
+        assert!(verify(&chunk).unwrap_err().starts_with("Stack underflow"));
+
+        let mut chunk = Chunk::default();
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Return, 1);
+        assert!(verify(&chunk).unwrap_err().starts_with("Unbalanced stack"));
+
+        let mut chunk = Chunk::default();
+        chunk.write_opcode(OpCode::Nil, 1);
+        assert_eq!(verify(&chunk).unwrap_err(), "Chunk does not end with a return");
+
+        let mut chunk = Chunk::default();
+        chunk.write(200, 1);
+        assert!(verify(&chunk).is_err());
+    }
+}
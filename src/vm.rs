@@ -1,33 +1,64 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::builtins::builtins;
 use crate::chunk::Chunk;
 use crate::compiler::compile;
+use crate::coverage::Coverage;
+use crate::diagnostic::{Diagnostic, Renderer};
 use crate::opcode::OpCode;
-use crate::value::Value;
+use crate::profiler::Profiler;
+use crate::table::Table;
+use crate::value::{Native, Value};
+use crate::verifier::verify;
+
+pub const STACK_MAX: usize = 256;
 
-const STACK_MAX: usize = 256;
+/// How many instructions run between checks of the deadline and interrupt flag.
+const POLL_INTERVAL: usize = 1024;
 
 struct Stack {
     pub values: [Value; STACK_MAX],
     pub top: usize,
+    // heap bytes owned by the values on the stack, and what each slot added to
+    // it: a list may grow while on the stack, so popping must not measure again
+    pub bytes: usize,
+    sizes: [usize; STACK_MAX],
 }
 
 impl Stack {
     fn new() -> Self {
         const NIL: Value = Value::Nil;
-        Self { values: [NIL; STACK_MAX], top: 0 }
+        Self { values: [NIL; STACK_MAX], top: 0, bytes: 0, sizes: [0; STACK_MAX] }
     }
 
     pub fn reset(&mut self) {
+        for value in &mut self.values[..self.top] {
+            *value = Value::Nil;
+        }
         self.top = 0;
+        self.bytes = 0;
     }
 
     pub fn push(&mut self, value: Value) {
+        let size = value.heap_size();
+        self.bytes += size;
+        self.sizes[self.top] = size;
         self.values[self.top] = value;
         self.top += 1;
     }
 
     pub fn pop(&mut self) -> Value {
         self.top -= 1;
-        std::mem::replace(&mut self.values[self.top], Value::Nil)
+        let value = std::mem::replace(&mut self.values[self.top], Value::Nil);
+        self.bytes -= self.sizes[self.top];
+        value
     }
 
     pub fn peek(&self, distance: usize) -> &Value {
@@ -35,10 +66,61 @@ impl Stack {
     }
 }
 
+/// Applies an arithmetic operator to two operands and pushes the result.
+/// Numbers are handled inline, everything else goes through the matching `Value` method.
+macro_rules! arithmetic {
+    ($vm:expr, $a:expr, $b:expr, $op:tt, $method:ident) => {
+        match (&$a, &$b) {
+            (Value::Number(x), Value::Number(y)) => $vm.stack.push(Value::Number(x $op y)),
+            _ => match $a.$method(&$b) {
+                Ok(value) => $vm.stack.push(value),
+                Err(msg) => {
+                    return $vm.runtime_error(&msg);
+                }
+            },
+        }
+    };
+}
+
+/// Bounds on how long a single run may take.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub max_instructions: Option<usize>,
+    pub timeout: Option<Duration>,
+    /// Ceiling on heap bytes held by live values.
+    pub max_memory: Option<usize>,
+}
+
+/// Lets a debugger observe and pause execution. `run` calls the hook before the
+/// first instruction of each new line, according to `Chunk::lines`; the hook may
+/// inspect the VM and block for as long as it likes.
+pub trait DebugHook {
+    /// Returns `false` to stop the run, which then ends as interrupted.
+    fn on_line(&mut self, vm: &mut VM, line: usize) -> bool;
+}
+
 pub struct VM {
     chunk: Chunk,
     ip: usize,
     stack: Stack,
+    instruction_count: usize,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    limits: Limits,
+    deadline: Option<Instant>,
+    interrupt: Arc<AtomicBool>,
+    globals: HashMap<String, Value>,
+    // natives such as `len`, found when no global has the name
+    builtins: HashMap<String, Value>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    debug_hook: Option<Box<dyn DebugHook>>,
+    // the line the debug hook was last called for
+    last_line: usize,
+    result: Option<Value>,
+    // renders errors against the source, which is kept from `interpret` while it is set
+    renderer: Option<Renderer>,
+    source: String,
 }
 
 impl VM {
@@ -47,37 +129,318 @@ impl VM {
             chunk, 
             ip: 0, 
             stack: Stack::new(),
+            instruction_count: 0,
+            profiler: None,
+            coverage: None,
+            limits: Limits::default(),
+            deadline: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            globals: HashMap::new(),
+            builtins: builtins(),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            debug_hook: None,
+            last_line: 0,
+            result: None,
+            renderer: None,
+            source: String::new(),
         }
     }
 
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
         let chunk = match compile(source) {
             Ok(chunk) => chunk,
-            Err(_) => return InterpretResult::CompileError,
+            Err(errors) => {
+                for error in errors {
+                    let _ = match &self.renderer {
+                        Some(renderer) => write!(self.stderr, "{}", renderer.render(&error.diagnostic(), source)),
+                        None => writeln!(self.stderr, "{}", error),
+                    };
+                }
+                return InterpretResult::CompileError;
+            }
         };
+        if self.renderer.is_some() {
+            self.source = source.to_string();
+        }
+        let result = self.interpret_chunk(chunk);
+        self.source.clear();
+        result
+    }
+
+    /// Runs an already compiled chunk, e.g. one loaded from a `.loxc` file.
+    /// The chunk is verified first, whether or not it came from our compiler,
+    /// so a malformed one is rejected instead of overflowing the stack.
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> InterpretResult {
+        if let Err(msg) = verify(&chunk) {
+            let _ = writeln!(self.stderr, "Invalid chunk: {}", msg);
+            return InterpretResult::CompileError;
+        }
+        self.run_chunk(chunk)
+    }
+
+    fn run_chunk(&mut self, chunk: Chunk) -> InterpretResult {
         self.chunk = chunk;
-        self.run()
+        self.ip = 0;
+        self.stack.reset();
+        self.instruction_count = 0;
+        self.last_line = 0;
+        self.result = None;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        // an interrupt meant for an earlier run must not abort this one
+        self.interrupt.store(false, Ordering::Relaxed);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.start(&self.chunk.name);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.instrument(&self.chunk);
+        }
+        let result = self.run();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.finish();
+        }
+        result
+    }
+
+    /// Starts collecting a profile of every following run.
+    pub fn enable_profiler(&mut self) {
+        self.profiler.get_or_insert_with(Profiler::new);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Starts recording which lines of every following run execute.
+    pub fn enable_coverage(&mut self) {
+        self.coverage.get_or_insert_with(Coverage::new);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// A flag that aborts the current run when set from another thread or a
+    /// native function. The VM clears it when a run starts and once the run
+    /// has been aborted.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    fn check_limits(&mut self) -> Option<AbortReason> {
+        if self.limits.max_instructions.is_some_and(|max| self.instruction_count >= max) {
+            return Some(AbortReason::InstructionBudget);
+        }
+        if self.instruction_count.is_multiple_of(POLL_INTERVAL) {
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                return Some(AbortReason::Interrupted);
+            }
+            if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Some(AbortReason::Deadline);
+            }
+        }
+        None
+    }
+
+    pub fn set_debug_hook(&mut self, hook: impl DebugHook + 'static) {
+        self.debug_hook = Some(Box::new(hook));
+    }
+
+    /// The line of the next instruction to run.
+    pub fn current_line(&self) -> usize {
+        self.chunk.lines.get(self.ip).copied().unwrap_or(0)
+    }
+
+    /// Number of active call frames. Only the script itself runs as a frame for
+    /// now, since natives execute without one.
+    pub fn call_depth(&self) -> usize {
+        1
+    }
+
+    /// The active frames as (function, line), innermost first.
+    pub fn stack_trace(&self) -> Vec<(String, usize)> {
+        vec![(self.chunk.name.clone(), self.current_line())]
+    }
+
+    /// The temporaries on the value stack, bottom first.
+    pub fn stack_values(&self) -> &[Value] {
+        &self.stack.values[..self.stack.top]
+    }
+
+    /// All globals, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, &Value)> {
+        let mut globals: Vec<(&str, &Value)> = self.globals.iter().map(|(name, value)| (name.as_str(), value)).collect();
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+        globals
+    }
+
+    /// The value the last completed run returned.
+    pub fn result(&self) -> Option<&Value> {
+        self.result.as_ref()
+    }
+
+    /// Evaluates an expression against this VM's globals without disturbing a
+    /// paused run. Assignments made by the expression are kept.
+    pub fn evaluate(&mut self, source: &str) -> Result<Value, String> {
+        let chunk = compile(source)
+            .map_err(|errors| errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))?;
+        let mut vm = VM::default();
+        let stderr = OutputBuffer::new();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(stderr.clone());
+        vm.limits = self.limits;
+        vm.globals = std::mem::take(&mut self.globals);
+        let result = vm.interpret_chunk(chunk);
+        self.globals = vm.globals;
+        match (result, vm.result) {
+            (InterpretResult::Ok, Some(value)) => Ok(value),
+            _ => Err(stderr.contents().trim_end().to_string()),
+        }
+    }
+
+    /// Reports compile and runtime errors with the offending source underlined,
+    /// instead of the default one line per error.
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = Some(renderer);
+    }
+
+    /// Redirects the output of scripts, which defaults to the process's stdout.
+    pub fn set_stdout(&mut self, stdout: impl Write + 'static) {
+        self.stdout = Box::new(stdout);
+    }
+
+    /// Redirects compile and runtime error messages, which default to the process's stderr.
+    pub fn set_stderr(&mut self, stderr: impl Write + 'static) {
+        self.stderr = Box::new(stderr);
+    }
+
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        match &self.renderer {
+            // chunks loaded without their source can only be reported plainly
+            Some(renderer) if !self.source.is_empty() => {
+                // every byte of an instruction has its span, so the one before `ip` will do
+                let offset = self.ip.saturating_sub(1);
+                let line = self.chunk.lines.get(offset).copied().unwrap_or(0);
+                let span = self.chunk.spans.get(offset).copied().unwrap_or_default();
+                let diagnostic = Diagnostic::error(message, line, span);
+                let _ = write!(self.stderr, "{}", renderer.render(&diagnostic, &self.source));
+            }
+            _ => {
+                let _ = writeln!(self.stderr, "{}", message);
+            }
+        }
+        InterpretResult::RuntimeError
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    /// Defines or overwrites a global variable.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.globals.insert(name.to_string(), value.into());
+    }
+
+    /// Defines a global function implemented in Rust.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        self.set_global(name, Value::Native(Rc::new(Native::new(name, arity, function))));
+    }
+
+    /// Calls the global function `name` with `args` and returns its result.
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        let callee = self
+            .globals
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RuntimeError::new(format!("Undefined function '{}'", name)))?;
+        call_value(&callee, args).map_err(RuntimeError::new)
+    }
+
+    /// Heap bytes currently held by values on the stack.
+    pub fn bytes_allocated(&self) -> usize {
+        self.stack.bytes
+    }
+
+    /// Fails if allocating `bytes` more would exceed the memory limit.
+    /// Checked before allocating so the host never has to abort.
+    fn allocate(&self, bytes: usize) -> Result<(), String> {
+        match self.limits.max_memory {
+            Some(max) if self.stack.bytes + bytes > max => Err("Out of memory".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of instructions dispatched by the most recent run.
+    pub fn instructions_executed(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let const_idx: u8 = self.chunk.code[self.ip];
+        self.ip += 1;
+        self.chunk.get_const(const_idx as usize)
     }
 
     fn run(&mut self) -> InterpretResult {
         loop {
-            let instruction = OpCode::from(self.chunk.code[self.ip]);
-            self.ip += 1;
+            if let Some(reason) = self.check_limits() {
+                let _ = writeln!(self.stderr, "Execution aborted: {}", reason);
+                return InterpretResult::Aborted(reason);
+            }
 
             #[cfg(feature = "debug")]
             {
-                use crate::debug::disassemble_instruction;
-                print!("          ");
+                use crate::debug::decode_instruction;
+                let _ = write!(self.stdout, "          ");
                 for i in 0..self.stack.top {
-                    print!("[ {} ]", self.stack.values[i]);
+                    let _ = write!(self.stdout, "[ {} ]", self.stack.values[i]);
+                }
+                let _ = writeln!(self.stdout);
+                let _ = match decode_instruction(&self.chunk, self.ip) {
+                    Ok(instruction) => writeln!(self.stdout, "{}", instruction),
+                    Err(msg) => writeln!(self.stdout, "{:04} {}", self.ip, msg),
+                };
+            }
+
+            if self.debug_hook.is_some() && self.chunk.lines[self.ip] != self.last_line {
+                self.last_line = self.chunk.lines[self.ip];
+                let mut hook = self.debug_hook.take().unwrap();
+                let keep_running = hook.on_line(self, self.last_line);
+                self.debug_hook = Some(hook);
+                if !keep_running {
+                    return InterpretResult::Aborted(AbortReason::Interrupted);
                 }
-                println!();
-                disassemble_instruction(&self.chunk, self.ip);
             }
 
+            let instruction = match OpCode::try_from(self.chunk.code[self.ip]) {
+                Ok(instruction) => instruction,
+                Err(msg) => {
+                    return self.runtime_error(&msg);
+                }
+            };
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.enter(instruction, self.chunk.lines[self.ip]);
+            }
+            if let Some(coverage) = self.coverage.as_mut() {
+                coverage.hit(self.chunk.lines[self.ip]);
+            }
+            self.ip += 1;
+            self.instruction_count += 1;
+
             match instruction {
                 OpCode::Return => {
-                    println!("{}", self.stack.pop());
+                    let value: Value = self.stack.pop();
+                    let _ = writeln!(self.stdout, "{}", value);
+                    self.result = Some(value);
                     return InterpretResult::Ok;
                 }
                 OpCode::Nil => self.stack.push(Value::Nil),
@@ -86,73 +449,51 @@ impl VM {
                     match value.not() {
                         Ok(value) => self.stack.push(value),
                         Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
+                            return self.runtime_error(&msg);
                         }
                     }
                 }
                 OpCode::True => self.stack.push(Value::Bool(true)),
                 OpCode::False => self.stack.push(Value::Bool(false)),
                 OpCode::Add => {
+                    let bytes: usize = self.stack.peek(1).concat_len(self.stack.peek(0));
+                    if let Err(msg) = self.allocate(bytes) {
+                        return self.runtime_error(&msg);
+                    }
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
-                    match a.add(&b) {
-                        Ok(value) => self.stack.push(value),
-                        Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
-                        }
-                    }
+                    arithmetic!(self, a, b, +, add);
                 }
                 OpCode::Subtract => {
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
-                    match a.subtract(&b) {
-                        Ok(value) => self.stack.push(value),
-                        Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
-                        }
-                    }
+                    arithmetic!(self, a, b, -, subtract);
                 }
                 OpCode::Multiply => {
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
-                    match a.multiply(&b) {
-                        Ok(value) => self.stack.push(value),
-                        Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
-                        }
-                    }
+                    arithmetic!(self, a, b, *, multiply);
                 }
                 OpCode::Divide => {
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
-                    match a.divide(&b) {
-                        Ok(value) => self.stack.push(value),
-                        Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
-                        }
-                    }
+                    arithmetic!(self, a, b, /, divide);
                 }
                 OpCode::Modulo => {
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
-                    match a.modulo(&b) {
-                        Ok(value) => self.stack.push(value),
-                        Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
-                        }
-                    }
+                    arithmetic!(self, a, b, %, modulo);
                 }
                 OpCode::Equal => {
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
                     self.stack.push(a.equal(&b));
                 }
+                OpCode::NotEqual => {
+                    let b: Value = self.stack.pop();
+                    let a: Value = self.stack.pop();
+                    self.stack.push(a.not_equal(&b));
+                }
                 OpCode::Greater => {
                     let b: Value = self.stack.pop();
                     let a: Value = self.stack.pop();
@@ -163,20 +504,167 @@ impl VM {
                     let a: Value = self.stack.pop();
                     self.stack.push(a.less(&b));
                 }
+                OpCode::GreaterEqual => {
+                    let b: Value = self.stack.pop();
+                    let a: Value = self.stack.pop();
+                    self.stack.push(a.greater_equal(&b));
+                }
+                OpCode::LessEqual => {
+                    let b: Value = self.stack.pop();
+                    let a: Value = self.stack.pop();
+                    self.stack.push(a.less_equal(&b));
+                }
                 OpCode::Negate => {
                     let value: Value = self.stack.pop();
                     match value.negate() {
                         Ok(value) => self.stack.push(value),
                         Err(msg) => {
-                            eprintln!("{}", msg);
-                            return InterpretResult::RuntimeError;
+                            return self.runtime_error(&msg);
                         }
                     }
                 }
                 OpCode::Constant => {
-                    let const_idx: u8 = self.chunk.code[self.ip];
+                    let value: Value = self.read_constant();
+                    if let Err(msg) = self.allocate(value.heap_size()) {
+                        return self.runtime_error(&msg);
+                    }
+                    self.stack.push(value);
+                }
+                OpCode::AddConstant => {
+                    let b: Value = self.read_constant();
+                    if let Err(msg) = self.allocate(self.stack.peek(0).concat_len(&b)) {
+                        return self.runtime_error(&msg);
+                    }
+                    let a: Value = self.stack.pop();
+                    arithmetic!(self, a, b, +, add);
+                }
+                OpCode::GetGlobal => {
+                    let name: String = self.read_constant().to_string();
+                    match self.globals.get(&name).or_else(|| self.builtins.get(&name)).cloned() {
+                        Some(value) => {
+                            if let Err(msg) = self.allocate(value.heap_size()) {
+                                return self.runtime_error(&msg);
+                            }
+                            self.stack.push(value);
+                        }
+                        None => {
+                            return self.runtime_error(&format!("Undefined variable '{}'", name));
+                        }
+                    }
+                }
+                OpCode::SetGlobal => {
+                    // assignment is an expression, so the value stays on the stack
+                    let name: String = self.read_constant().to_string();
+                    let value: Value = self.stack.peek(0).clone();
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            return self.runtime_error(&format!("Undefined variable '{}'", name));
+                        }
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count: usize = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    let args_start: usize = self.stack.top - arg_count;
+                    let result = call_value(self.stack.peek(arg_count), &self.stack.values[args_start..self.stack.top]);
+                    match result {
+                        Ok(value) => {
+                            for _ in 0..=arg_count {
+                                self.stack.pop();
+                            }
+                            // builtins such as push return the list they grew
+                            if let Err(msg) = self.allocate(value.heap_size()) {
+                                return self.runtime_error(&msg);
+                            }
+                            self.stack.push(value);
+                        }
+                        Err(msg) => {
+                            return self.runtime_error(&msg);
+                        }
+                    }
+                }
+                OpCode::LessConstant => {
+                    let b: Value = self.read_constant();
+                    let a: Value = self.stack.pop();
+                    self.stack.push(a.less(&b));
+                }
+                OpCode::BuildList => {
+                    let count: usize = self.chunk.code[self.ip] as usize;
+                    self.ip += 1;
+                    // the items only move, but the list needs room for them
+                    if let Err(msg) = self.allocate(Value::slots_size(count)) {
+                        return self.runtime_error(&msg);
+                    }
+                    let mut items: Vec<Value> = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        items.push(self.stack.pop());
+                    }
+                    items.reverse();
+                    self.stack.push(Value::list(items));
+                }
+                OpCode::BuildMap => {
+                    let count: usize = self.chunk.code[self.ip] as usize;
                     self.ip += 1;
-                    let value: Value = self.chunk.get_const(const_idx as usize);
+                    let start: usize = self.stack.top - count * 2;
+                    if let Err(msg) = self.allocate(Value::slots_size(count * 2)) {
+                        return self.runtime_error(&msg);
+                    }
+                    let mut table = Table::new();
+                    // a repeated key keeps its first position and its last value
+                    // the pairs are moved out rather than copied; popping them below
+                    // releases the bytes their slots accounted for
+                    for pair in self.stack.values[start..self.stack.top].chunks_mut(2) {
+                        let key = std::mem::replace(&mut pair[0], Value::Nil);
+                        let value = std::mem::replace(&mut pair[1], Value::Nil);
+                        if let Err(msg) = table.insert(key, value) {
+                            return self.runtime_error(&msg);
+                        }
+                    }
+                    for _ in 0..count * 2 {
+                        self.stack.pop();
+                    }
+                    self.stack.push(Value::map(table));
+                }
+                OpCode::Stringify => {
+                    // strings are left as they are rather than copied
+                    if !matches!(self.stack.peek(0), Value::String(_)) {
+                        let value: Value = self.stack.pop();
+                        // formatting gives up at the limit instead of building the whole text first
+                        let room = match self.limits.max_memory {
+                            Some(max) => max.saturating_sub(self.stack.bytes),
+                            None => usize::MAX,
+                        };
+                        match value.to_string_within(room) {
+                            Some(text) => self.stack.push(Value::String(text)),
+                            None => {
+                                return self.runtime_error("Out of memory");
+                            }
+                        }
+                    }
+                }
+                OpCode::IndexGet => {
+                    let index: Value = self.stack.pop();
+                    let target: Value = self.stack.pop();
+                    match target.index(&index) {
+                        Ok(value) => self.stack.push(value),
+                        Err(msg) => {
+                            return self.runtime_error(&msg);
+                        }
+                    }
+                }
+                OpCode::IndexSet => {
+                    // like other assignments this is an expression, leaving the value on the stack
+                    // while the target keeps its own copy
+                    if let Err(msg) = self.allocate(self.stack.peek(0).heap_size()) {
+                        return self.runtime_error(&msg);
+                    }
+                    let value: Value = self.stack.pop();
+                    let index: Value = self.stack.pop();
+                    let target: Value = self.stack.pop();
+                    if let Err(msg) = target.set_index(&index, value.clone()) {
+                        return self.runtime_error(&msg);
+                    }
                     self.stack.push(value);
                 }
             }
@@ -190,8 +678,331 @@ impl Default for VM {
     }
 }
 
+/// A cloneable in-memory sink, so output handed to the VM can be read back.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).to_string()
+    }
+
+    pub fn clear(&self) {
+        self.buffer.borrow_mut().clear();
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn call_value(callee: &Value, args: &[Value]) -> Result<Value, String> {
+    match callee {
+        Value::Native(native) => native.call(args),
+        _ => Err(format!("Can only call functions, not {}", callee)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into() }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    Aborted(AbortReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AbortReason {
+    InstructionBudget,
+    Deadline,
+    Interrupted,
+}
+
+impl Display for AbortReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InstructionBudget => write!(f, "instruction budget exceeded"),
+            Self::Deadline => write!(f, "deadline exceeded"),
+            Self::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_budget() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        vm.set_limits(Limits { max_instructions: Some(1), ..Limits::default() });
+        assert_eq!(vm.interpret("1 + 2"), InterpretResult::Aborted(AbortReason::InstructionBudget));
+        assert_eq!(vm.instructions_executed(), 1);
+        // the VM is usable again after an abort
+        vm.set_limits(Limits { max_instructions: Some(2), ..Limits::default() });
+        assert_eq!(vm.interpret("1 + 2"), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        vm.set_limits(Limits { timeout: Some(Duration::ZERO), ..Limits::default() });
+        assert_eq!(vm.interpret("1"), InterpretResult::Aborted(AbortReason::Deadline));
+        vm.set_limits(Limits::default());
+        assert_eq!(vm.interpret("1"), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut chunk = Chunk::default();
+        for s in ["abc", "def"] {
+            let idx = chunk.add_const(Value::String(s.to_string())) as u8;
+            chunk.write_opcode(OpCode::Constant, 1);
+            chunk.write(idx, 1);
+        }
+        chunk.write_opcode(OpCode::Add, 1);
+        chunk.write_opcode(OpCode::Return, 1);
+
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        vm.set_limits(Limits { max_memory: Some(12), ..Limits::default() });
+        assert_eq!(vm.interpret_chunk(chunk.clone()), InterpretResult::Ok);
+        // both operands and the result are alive while concatenating
+        vm.set_limits(Limits { max_memory: Some(11), ..Limits::default() });
+        assert_eq!(vm.interpret_chunk(chunk.clone()), InterpretResult::RuntimeError);
+        assert_eq!(vm.bytes_allocated(), 6);
+        vm.set_limits(Limits { max_memory: Some(5), ..Limits::default() });
+        assert_eq!(vm.interpret_chunk(chunk), InterpretResult::RuntimeError);
+        assert_eq!(vm.bytes_allocated(), 3);
+        assert_eq!(vm.interpret("\"ab\""), InterpretResult::Ok);
+        assert_eq!(vm.bytes_allocated(), 0);
+    }
+
+    #[test]
+    fn test_memory_limit_counts_collections() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        vm.set_global("s", "x".repeat(2000));
+        vm.set_global("l", Value::list(Vec::new()));
+        vm.set_global("c", Value::list(vec![Value::from("abc")]));
+        vm.set_limits(Limits { max_memory: Some(2500), ..Limits::default() });
+        assert_eq!(vm.interpret("[[s]]"), InterpretResult::Ok);
+        assert_eq!(vm.interpret("[[s], [s], [s], [s]]"), InterpretResult::RuntimeError);
+        assert_eq!(vm.interpret("{\"a\": s, \"b\": s}"), InterpretResult::RuntimeError);
+        // the list grows through push, so reading it again costs its new size
+        assert_eq!(vm.interpret("[push(l, s), l]"), InterpretResult::RuntimeError);
+        // a list that contains itself is only counted once
+        assert_eq!(vm.interpret("push(c, c)"), InterpretResult::Ok);
+        assert_eq!(vm.get_global("c").unwrap().heap_size(), 3 + Value::slots_size(2));
+        assert_eq!(vm.bytes_allocated(), 0);
+
+        // building a collection takes room for its slots
+        vm.set_limits(Limits { max_memory: Some(Value::slots_size(3)), ..Limits::default() });
+        assert_eq!(vm.interpret("[nil, nil, nil]"), InterpretResult::Ok);
+        assert_eq!(vm.interpret("[nil, nil, nil, nil]"), InterpretResult::RuntimeError);
+        assert_eq!(vm.interpret("{nil: true, false: 1}"), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_globals_and_natives() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        vm.set_global("x", 20.0);
+        vm.define_native("twice", 1, |args| match &args[0] {
+            Value::Number(n) => Ok(Value::Number(n * 2.0)),
+            other => Err(format!("Cannot double {}", other)),
+        });
+        assert_eq!(vm.interpret("y = twice(x) + 2"), InterpretResult::RuntimeError);
+        vm.set_global("y", Value::Nil);
+        assert_eq!(vm.interpret("y = twice(x) + 2"), InterpretResult::Ok);
+        assert_eq!(vm.get_global("y"), Some(Value::Number(42.0)));
+        assert_eq!(vm.interpret("z"), InterpretResult::RuntimeError);
+        assert_eq!(vm.interpret("twice(1, 2)"), InterpretResult::RuntimeError);
+
+        let result = vm.call_function("twice", &[Value::from(1.5)]).unwrap();
+        assert_eq!(f64::try_from(result), Ok(3.0));
+        assert_eq!(
+            vm.call_function("twice", &["a".into()]),
+            Err(RuntimeError::new("Cannot double a"))
+        );
+        assert!(vm.call_function("missing", &[]).is_err());
+        assert!(vm.call_function("x", &[]).is_err());
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut vm = VM::default();
+        let stdout = OutputBuffer::new();
+        vm.set_stdout(stdout.clone());
+        vm.set_stderr(io::sink());
+        vm.set_global("a", Value::Nil);
+        vm.set_global("b", Value::Nil);
+
+        assert_eq!(vm.interpret("b = a = [1, [2, 3], \"x\"]"), InterpretResult::Ok);
+        // `b` shares the list with `a`, so assigning through one is seen by the other
+        assert_eq!(vm.interpret("b[1][-1] = push(a, nil)[0] + len(a)"), InterpretResult::Ok);
+        assert_eq!(vm.interpret("a"), InterpretResult::Ok);
+        assert_eq!(stdout.contents(), "[1, [2, 3], \"x\"]\n5\n[1, [2, 5], \"x\", nil]\n");
+        assert_eq!(vm.interpret("a[4]"), InterpretResult::RuntimeError);
+        assert_eq!(vm.interpret("a[\"0\"]"), InterpretResult::RuntimeError);
+        assert_eq!(vm.interpret("nil[0]"), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mut vm = VM::default();
+        let stdout = OutputBuffer::new();
+        vm.set_stdout(stdout.clone());
+        vm.set_global("x", Value::list(vec![Value::from("a"), Value::Nil]));
+
+        assert_eq!(vm.interpret("\"x = ${x}, len = ${len(x) * 2}\""), InterpretResult::Ok);
+        assert_eq!(vm.interpret("\"${ {\"k\": \"${x[0]}}\"}[\"k\"] }\""), InterpretResult::Ok);
+        let template = "\"\"\"\n    <ul>\n      ${len(x)} items\n    </ul>\n    \"\"\"";
+        assert_eq!(vm.interpret(template), InterpretResult::Ok);
+        assert_eq!(stdout.contents(), "x = [\"a\", nil], len = 4\na}\n<ul>\n  2 items\n</ul>\n");
+    }
+
+    #[test]
+    fn test_stack_overflow_is_an_error() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        let nils = |count: usize| vec!["nil"; count].join(", ");
+        assert_eq!(vm.interpret(&format!("[{}, [{}]]", nils(200), nils(200))), InterpretResult::CompileError);
+        assert_eq!(vm.interpret(&format!("len({}, len({}))", nils(200), nils(200))), InterpretResult::CompileError);
+        assert_eq!(vm.interpret(&format!("[{}, [{}]]", nils(200), nils(55))), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn test_interpolation_memory_limit() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        // the shared inner list is held once but printed ten times
+        let inner = Value::list(vec![Value::from("x".repeat(500))]);
+        vm.set_global("l", Value::list(vec![inner; 10]));
+        vm.set_limits(Limits { max_memory: Some(1000), ..Limits::default() });
+        assert_eq!(vm.interpret("l"), InterpretResult::Ok);
+        assert_eq!(vm.interpret("\"${l[0]}\""), InterpretResult::Ok);
+        assert_eq!(vm.interpret("\"${l}\""), InterpretResult::RuntimeError);
+        assert_eq!(vm.bytes_allocated(), 0);
+        assert_eq!(Value::list(vec![Value::from("abc")]).to_string_within(6), None);
+        assert_eq!(Value::list(vec![Value::from("abc")]).to_string_within(7), Some("[\"abc\"]".to_string()));
+    }
+
+    #[test]
+    fn test_maps() {
+        let mut vm = VM::default();
+        let stdout = OutputBuffer::new();
+        vm.set_stdout(stdout.clone());
+        vm.set_stderr(io::sink());
+        vm.set_global("m", Value::Nil);
+
+        assert_eq!(vm.interpret("m = {\"b\": 1, 0: [], true: nil}"), InterpretResult::Ok);
+        assert_eq!(vm.interpret("m[-0] = m[\"a\"] = 2"), InterpretResult::Ok);
+        assert_eq!(vm.interpret("[keys(m), has(m, nil), remove(m, true), m]"), InterpretResult::Ok);
+        assert_eq!(
+            stdout.contents(),
+            "{\"b\": 1, 0: [], true: nil}\n2\n[[\"b\", 0, true, \"a\"], false, nil, {\"b\": 1, 0: 2, \"a\": 2}]\n"
+        );
+        assert_eq!(vm.interpret("m[\"c\"]"), InterpretResult::RuntimeError);
+        assert_eq!(vm.interpret("m[[]] = 1"), InterpretResult::RuntimeError);
+    }
+
+    #[test]
+    fn test_output_sinks() {
+        let mut vm = VM::default();
+        let stdout = OutputBuffer::new();
+        let stderr = OutputBuffer::new();
+        vm.set_stdout(stdout.clone());
+        vm.set_stderr(stderr.clone());
+
+        vm.interpret("\"a\" + \"b\"");
+        vm.interpret("-\"a\"");
+        vm.interpret("(1");
+        assert_eq!(stdout.contents(), "ab\n");
+        assert_eq!(stderr.contents(), "Cannot negate a\n[line 1] Error at end: Expect ')' after expression\n");
+    }
+
+    #[test]
+    fn test_rendered_errors() {
+        let mut vm = VM::default();
+        let stderr = OutputBuffer::new();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(stderr.clone());
+        vm.set_renderer(Renderer::new("main.lox", false));
+
+        vm.interpret("\"a\"\n  + -\"b\"");
+        assert_eq!(
+            stderr.contents(),
+            "error: Cannot negate b\n --> main.lox:2:5\n  |\n2 |   + -\"b\"\n  |     ^\n"
+        );
+        stderr.clear();
+        vm.interpret("(1 +\n  2");
+        assert_eq!(
+            stderr.contents(),
+            "error: Expect ')' after expression\n --> main.lox:2:4\n  |\n2 |   2\n  |    ^\n\
+             \x20 = note: unclosed '(' opened at 1:1\n  = help: did you forget a ')'?\n"
+        );
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut vm = VM::default();
+        vm.set_stdout(io::sink());
+        vm.set_stderr(io::sink());
+        let interrupt = vm.interrupt_handle();
+        let handle = interrupt.clone();
+        vm.define_native("stop", 0, move |_| {
+            handle.store(true, Ordering::Relaxed);
+            Ok(Value::Nil)
+        });
+        // enough instructions after the call to reach the next poll of the flag
+        let nils = format!("[{}]", ["nil"; 200].join(", "));
+        let source = format!("[stop(), {}]", [nils.as_str(); 6].join(", "));
+        assert_eq!(vm.interpret(&source), InterpretResult::Aborted(AbortReason::Interrupted));
+        assert!(!interrupt.load(Ordering::Relaxed));
+
+        // a flag set between runs, e.g. by a Ctrl-C just after a script finished, is ignored
+        interrupt.store(true, Ordering::Relaxed);
+        assert_eq!(vm.interpret("1"), InterpretResult::Ok);
+    }
 }
\ No newline at end of file
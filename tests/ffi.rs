@@ -0,0 +1,33 @@
+//! Builds tests/ffi/main.c against the cdylib and runs it.
+#![cfg(target_os = "linux")]
+
+use std::{env, path::PathBuf, process::Command};
+
+#[test]
+fn test_c_program() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // the test binary lives in target/<profile>/deps, next to the profile's liblox.so
+    let lib_dir = env::current_exe().unwrap().parent().unwrap().parent().unwrap().to_path_buf();
+    assert!(lib_dir.join("liblox.so").exists(), "liblox.so was not built in {}", lib_dir.display());
+
+    let program = env::temp_dir().join(format!("lox-ffi-test-{}", std::process::id()));
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(&cc)
+        .arg(manifest_dir.join("tests/ffi/main.c"))
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .args(["-llox", "-Wall", "-Werror", "-o"])
+        .arg(&program)
+        .status()
+        .unwrap_or_else(|err| panic!("Failed to run {}: {}", cc, err));
+    assert!(status.success(), "Failed to compile the C test program");
+
+    let output = Command::new(&program).output().unwrap();
+    let _ = std::fs::remove_file(&program);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // the VM prints the value of the first script before the program's own "ok"
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, C\nok\n");
+}
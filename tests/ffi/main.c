@@ -0,0 +1,68 @@
+/* Exercises the C API end to end; built and run by tests/ffi.rs. */
+#include <stdio.h>
+#include <string.h>
+
+#include "lox.h"
+
+#define CHECK(condition)                                                  \
+    do {                                                                  \
+        if (!(condition)) {                                               \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,        \
+                    __LINE__, #condition);                                \
+            return 1;                                                     \
+        }                                                                 \
+    } while (0)
+
+static LoxValue *greet(void *userdata, const LoxValue *const *args, int argc) {
+    int *calls = userdata;
+    char buffer[64];
+    (*calls)++;
+    if (argc != 1 || lox_value_type(args[0]) != LOX_STRING) {
+        return NULL;
+    }
+    snprintf(buffer, sizeof buffer, "%s, %s", "Hello",
+             lox_value_as_string(args[0]));
+    return lox_value_string(buffer);
+}
+
+int main(void) {
+    int calls = 0;
+    LoxVM *vm = lox_vm_new();
+    LoxValue *value;
+
+    CHECK(lox_define_native(vm, "greet", 1, greet, &calls));
+    value = lox_value_string("C");
+    CHECK(lox_set_global(vm, "name", value));
+    lox_value_free(value);
+    value = lox_value_nil();
+    CHECK(lox_set_global(vm, "message", value));
+    lox_value_free(value);
+
+    CHECK(lox_interpret(vm, "message = greet(name)") == LOX_OK);
+    CHECK(lox_last_error(vm) == NULL);
+    CHECK(calls == 1);
+    value = lox_get_global(vm, "message");
+    CHECK(value != NULL);
+    CHECK(lox_value_type(value) == LOX_STRING);
+    CHECK(strcmp(lox_value_as_string(value), "Hello, C") == 0);
+    lox_value_free(value);
+
+    CHECK(lox_interpret(vm, "greet(1)") == LOX_RUNTIME_ERROR);
+    CHECK(strcmp(lox_last_error(vm), "Native function 'greet' failed") == 0);
+
+    CHECK(lox_interpret(vm, "1 +") == LOX_COMPILE_ERROR);
+    CHECK(strcmp(lox_last_error(vm), "[line 1] Error at end: Expect expression") == 0);
+
+    value = lox_value_number(2.5);
+    CHECK(lox_value_as_number(value) == 2.5);
+    CHECK(lox_value_as_bool(value));
+    lox_value_free(value);
+    value = lox_value_nil();
+    CHECK(lox_value_type(value) == LOX_NIL && !lox_value_as_bool(value));
+    lox_value_free(value);
+
+    CHECK(lox_get_global(vm, "missing") == NULL);
+    lox_vm_free(vm);
+    printf("ok\n");
+    return 0;
+}